@@ -23,6 +23,10 @@ pub enum GrufError {
     SerializationError(String),
     #[error("dyn_alloc error")]
     DynAllocError,
+    #[error("unsafe entry path: {0}")]
+    UnsafePath(String),
+    #[error("invalid patch list: {0}")]
+    InvalidPatchList(String),
 }
 
 impl GrufError {
@@ -37,4 +41,12 @@ impl GrufError {
     pub fn serialization_error(msg: impl Into<String>) -> Self {
         Self::SerializationError(msg.into())
     }
+
+    pub fn unsafe_path(msg: impl Into<String>) -> Self {
+        Self::UnsafePath(msg.into())
+    }
+
+    pub fn invalid_patch_list(msg: impl Into<String>) -> Self {
+        Self::InvalidPatchList(msg.into())
+    }
 }
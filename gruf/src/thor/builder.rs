@@ -5,13 +5,16 @@ use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
 use crate::archive::{serialize_as_win1252_str_into, serialize_to_win1252, GenericFileEntry};
 use crate::thor::{
-    ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
+    ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, SIGNATURE_FILE_NAME,
+    THOR_HEADER_MAGIC,
 };
-use crate::Result;
+use crate::{GrufError, Result};
 use crc::crc32::{self, Hasher32};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 
 const THOR_HEADER_FIXED_SIZE: usize = THOR_HEADER_MAGIC.len() + 0x8;
 
@@ -22,11 +25,13 @@ pub struct ThorArchiveBuilder<W: Write + Seek> {
     use_grf_merging: bool,
     target_grf_name: String,
     include_checksums: bool,
+    signing_keypair: Option<Keypair>,
 }
 
 struct BuilderFileEntry {
     generic: GenericFileEntry,
     checksum: u32,
+    sha256: [u8; 32],
 }
 
 #[derive(Debug, Serialize)]
@@ -79,20 +84,29 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
             use_grf_merging,
             target_grf_name,
             include_checksums,
+            signing_keypair: None,
         })
     }
 
+    /// Signs the archive with the given Ed25519 secret key when it's finished.
+    ///
+    /// Returns the public key that matches `secret_key`.
+    pub fn sign_with(&mut self, secret_key: &[u8]) -> Result<Vec<u8>> {
+        let secret = SecretKey::from_bytes(secret_key)
+            .map_err(|_| GrufError::serialization_error("Invalid signing key"))?;
+        let public = PublicKey::from(&secret);
+        self.signing_keypair = Some(Keypair { secret, public });
+        Ok(public.as_bytes().to_vec())
+    }
+
     pub fn append_file_update<R>(&mut self, entry_path: String, mut data: R) -> Result<()>
     where
         R: Read,
     {
         // Compress it
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        let (data_size, data_checksum) = if self.include_checksums {
-            copy_and_measure_crc32(data.by_ref(), &mut encoder)?
-        } else {
-            (io::copy(data.by_ref(), &mut encoder)?, 0)
-        };
+        let (data_size, data_checksum, data_sha256) =
+            copy_and_measure(data.by_ref(), &mut encoder)?;
         // Write compressed data
         let compressed_data = encoder.finish()?;
         let compressed_data_size = compressed_data.len();
@@ -109,6 +123,7 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
                     size_compressed: u32::try_from(compressed_data_size)?,
                 },
                 checksum: data_checksum,
+                sha256: data_sha256,
             }),
         );
         Ok(())
@@ -128,6 +143,10 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         if self.include_checksums {
             self.append_data_integrity()?;
         }
+        // Append 'data.signature' if needed
+        if self.signing_keypair.is_some() {
+            self.append_data_signature()?;
+        }
         let (file_table_offset, compressed_table_size) = self.write_file_table()?;
         // Update the header
         self.obj.seek(SeekFrom::Start(0))?;
@@ -198,6 +217,49 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         });
         serialize_to_win1252(content.as_str())
     }
+
+    fn append_data_signature(&mut self) -> Result<()> {
+        let data_signature_content = self.generate_data_signature()?;
+        self.append_file_update(
+            SIGNATURE_FILE_NAME.to_string(),
+            data_signature_content.as_slice(),
+        )?;
+        Ok(())
+    }
+
+    /// Generates the content of 'data.signature', which is made of the
+    /// signature followed by the signed manifest.
+    fn generate_data_signature(&self) -> Result<Vec<u8>> {
+        let keypair = match &self.signing_keypair {
+            Some(v) => v,
+            None => return Err(GrufError::serialization_error("No signing key")),
+        };
+        let mut entries: Vec<(&String, &Option<BuilderFileEntry>)> = self
+            .entries
+            .iter()
+            .filter(|(relative_path, _)| relative_path.as_str() != INTEGRITY_FILE_NAME)
+            .collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        // The header is included in the manifest so that it cannot be tampered
+        // with either
+        let header = format!(
+            "use_grf_merging={}\r\ntarget_grf_name={}\r\n",
+            if self.use_grf_merging { 1 } else { 0 },
+            self.target_grf_name
+        );
+        let content = entries.into_iter().fold(header, |acc, v| {
+            if let Some(entry) = v.1 {
+                acc + format!("{}={}\r\n", v.0, hex::encode(entry.sha256)).as_str()
+            } else {
+                acc + format!("{}=removed\r\n", v.0).as_str()
+            }
+        });
+        let manifest = serialize_to_win1252(content.as_str())?;
+        let signature = keypair.sign(manifest.as_slice());
+        let mut data_signature_content = signature.to_bytes().to_vec();
+        data_signature_content.extend(manifest);
+        Ok(data_signature_content)
+    }
 }
 
 impl<W: Write + Seek> Drop for ThorArchiveBuilder<W> {
@@ -262,11 +324,11 @@ fn serialize_thor_slice_into<W: Write>(mut writer: W, slice: &[u8]) -> Result<()
     Ok(())
 }
 
-/// Computes a CRC32 checksum from a reader.
-fn copy_and_measure_crc32<R: ?Sized, W: ?Sized>(
+/// Computes CRC32 and SHA-256 checksums from a reader.
+fn copy_and_measure<R: ?Sized, W: ?Sized>(
     reader: &mut R,
     writer: &mut W,
-) -> Result<(u64, u32)>
+) -> Result<(u64, u32, [u8; 32])>
 where
     R: Read,
     W: Write,
@@ -274,15 +336,17 @@ where
     // Use an 8KiB buffer
     let mut buf = [0_u8; 8 * 1024];
     let mut digest = crc32::Digest::new(crc::crc32::IEEE);
+    let mut sha256 = Sha256::new();
     let mut written = 0;
     loop {
         let len = match reader.read(&mut buf) {
-            Ok(0) => return Ok((written, digest.sum32())),
+            Ok(0) => return Ok((written, digest.sum32(), sha256.finalize().into())),
             Ok(len) => len,
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
             Err(e) => return Err(e.into()),
         };
         digest.write(&buf[..len]);
+        sha256.update(&buf[..len]);
         writer.write_all(&buf[..len])?;
         written += len as u64;
     }
@@ -412,4 +476,49 @@ mod tests {
             assert!(thor_archive.is_valid().unwrap());
         }
     }
+
+    #[test]
+    fn test_data_signature() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("builder.thor");
+        let secret_key = [7_u8; 32];
+        let other_public_key = {
+            let output_file = File::create(temp_dir.path().join("other.thor")).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, false).unwrap();
+            builder.sign_with(&[8_u8; 32]).unwrap()
+        };
+        let public_key = {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder =
+                ThorArchiveBuilder::new(output_file, true, Some("data.grf".to_string()), true)
+                    .unwrap();
+            let public_key = builder.sign_with(&secret_key).unwrap();
+            builder
+                .append_file_update("data\\test1".to_string(), [1_u8, 2, 3].as_ref())
+                .unwrap();
+            builder.append_file_removal("data\\test2".to_string());
+            public_key
+        };
+        {
+            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+            assert!(thor_archive.is_valid().unwrap());
+            assert!(thor_archive.is_signed_by([&public_key]).unwrap());
+            assert!(thor_archive
+                .is_signed_by([&other_public_key, &public_key])
+                .unwrap());
+            assert!(!thor_archive.is_signed_by([&other_public_key]).unwrap());
+        }
+        {
+            // Unsigned archives don't contain any signature
+            let output_path = temp_dir.path().join("unsigned.thor");
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, false).unwrap();
+            builder.finish().unwrap();
+            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+            assert!(matches!(
+                thor_archive.is_signed_by([&public_key]).unwrap_err(),
+                GrufError::EntryNotFound
+            ));
+        }
+    }
 }
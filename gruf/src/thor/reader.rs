@@ -1,20 +1,24 @@
 use std::boxed::Box;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
 use crate::thor::{
-    ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
+    ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, SIGNATURE_FILE_NAME,
+    THOR_HEADER_MAGIC,
 };
 use crate::{GrufError, Result};
 use crc::crc32;
+use ed25519_dalek::{PublicKey, Signature, Verifier, SIGNATURE_LENGTH};
 use encoding::label::encoding_from_whatwg_label;
 use encoding::DecoderTrap;
 use flate2::read::ZlibDecoder;
 use nom::number::complete::{le_i16, le_i32, le_u32, le_u8};
 use nom::*;
+use sha2::{Digest, Sha256};
 
 // Packed structs' sizes in bytes
 const MAX_FILE_NAME_SIZE: usize = 256;
@@ -24,18 +28,106 @@ const SINGLE_FILE_ENTRY_MAX_SIZE: usize = 9 + MAX_FILE_NAME_SIZE;
 pub type ThorPatchList = Vec<ThorPatchInfo>;
 
 /// Parses Thor's plist.txt file
+///
+/// Invalid lines are ignored, use `parse_patch_list` to get diagnostics.
 pub fn patch_list_from_string(content: &str) -> ThorPatchList {
-    let vec_lines: Vec<&str> = content.lines().collect();
-    let mut sorted_patch_list: ThorPatchList = vec_lines
-        .into_iter()
-        .filter_map(|elem| ThorPatchInfo::from_string(&elem))
-        .collect();
+    match parse_patch_list(content, PatchListParsingMode::Lenient) {
+        Ok((patch_list, _)) => patch_list,
+        Err(_) => ThorPatchList::new(),
+    }
+}
+
+/// Indicates how problems found in a plist.txt file are handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatchListParsingMode {
+    Strict,  // Problems are errors
+    Lenient, // Problematic lines are ignored and reported as diagnostics
+}
+
+/// Problem found in a plist.txt file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatchListDiagnostic {
+    pub line_number: usize, // Starts at 1
+    pub message: String,
+}
+
+impl fmt::Display for PatchListDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line_number, self.message)
+    }
+}
+
+/// Parses Thor's plist.txt file and reports the problems found in it.
+///
+/// Blank lines and comments (lines starting with '//' or '#') are ignored.
+/// Malformed lines, unsafe file names (see `check_file_name`), trailing text as
+/// well as duplicate indices and file names are reported with their line
+/// number. In strict mode, any problem is an error. In lenient mode, the patch
+/// list is the one older versions of the patcher read (lines that don't start
+/// with an index and a file name are skipped, trailing text and duplicates are
+/// kept), minus the patches with unsafe file names, and problems are returned
+/// along with it.
+pub fn parse_patch_list(
+    content: &str,
+    mode: PatchListParsingMode,
+) -> Result<(ThorPatchList, Vec<PatchListDiagnostic>)> {
+    let mut patch_list = ThorPatchList::new();
+    let mut diagnostics = vec![];
+    let mut indices = HashSet::new();
+    let mut file_names = HashSet::new();
+    for (i, line) in content.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") || line.starts_with('#') {
+            continue;
+        }
+        let patch_info = match ThorPatchInfo::parse_line(line) {
+            Err(message) => {
+                diagnostics.push(PatchListDiagnostic {
+                    line_number,
+                    message,
+                });
+                continue;
+            }
+            Ok(v) => v,
+        };
+        if check_file_name(&patch_info.file_name).is_err() {
+            diagnostics.push(PatchListDiagnostic {
+                line_number,
+                message: format!("unsafe file name '{}'", patch_info.file_name),
+            });
+            continue;
+        }
+        if line.split_whitespace().nth(2).is_some() {
+            diagnostics.push(PatchListDiagnostic {
+                line_number,
+                message: format!("unexpected text after the file name in '{}'", line),
+            });
+        }
+        if !indices.insert(patch_info.index) {
+            diagnostics.push(PatchListDiagnostic {
+                line_number,
+                message: format!("duplicate patch index {}", patch_info.index),
+            });
+        }
+        if !file_names.insert(patch_info.file_name.clone()) {
+            diagnostics.push(PatchListDiagnostic {
+                line_number,
+                message: format!("duplicate file name '{}'", patch_info.file_name),
+            });
+        }
+        patch_list.push(patch_info);
+    }
+    if mode == PatchListParsingMode::Strict && !diagnostics.is_empty() {
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        return Err(GrufError::invalid_patch_list(messages.join(", ")));
+    }
     // Sort patch list by index
-    sorted_patch_list.sort_by(|a, b| a.index.cmp(&b.index));
-    sorted_patch_list
+    patch_list.sort_by_key(|patch_info| patch_info.index);
+    Ok((patch_list, diagnostics))
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ThorPatchInfo {
     pub index: usize,
     pub file_name: String,
@@ -44,24 +136,85 @@ pub struct ThorPatchInfo {
 impl ThorPatchInfo {
     /// Parses a line to extract patch index and patch file name.
     /// Returns a PatchInfo struct in case of success.
-    /// Returns None in case of failure
-    fn from_string(line: &str) -> Option<ThorPatchInfo> {
-        let words: Vec<_> = line.trim().split_whitespace().collect();
-        let index_str = words.get(0)?;
-        let index = match str::parse(index_str) {
-            Ok(v) => v,
-            Err(_) => {
-                return None;
-            }
-        };
-        let file_name = words.get(1)?;
-        Some(ThorPatchInfo {
+    /// Returns a description of the problem in case of failure
+    fn parse_line(line: &str) -> std::result::Result<ThorPatchInfo, String> {
+        let mut words = line.split_whitespace();
+        let index_str = words
+            .next()
+            .ok_or_else(|| format!("expected 'index file_name', got '{}'", line))?;
+        let file_name = words
+            .next()
+            .ok_or_else(|| "missing file name".to_string())?;
+        let index =
+            str::parse(index_str).map_err(|_| format!("invalid patch index '{}'", index_str))?;
+        Ok(ThorPatchInfo {
             index,
-            file_name: (*file_name).to_string(),
+            file_name: file_name.to_string(),
         })
     }
 }
 
+/// Checks that an entry's path stays inside of the directory it's extracted to.
+///
+/// Absolute paths, drive letters, traversal components, reserved device names
+/// and NUL bytes are rejected.
+pub fn check_relative_path(relative_path: &str) -> Result<()> {
+    const RESERVED_NAMES: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+    const RESERVED_NUMBERED_NAMES: &[&str] = &["COM", "LPT"];
+
+    if relative_path.is_empty() {
+        return Err(GrufError::unsafe_path("Path is empty"));
+    }
+    if relative_path.contains('\0') {
+        return Err(GrufError::unsafe_path("Path contains NUL bytes"));
+    }
+    if relative_path.starts_with('\\') || relative_path.starts_with('/') {
+        return Err(GrufError::unsafe_path("Path is absolute"));
+    }
+    // Catches drive letters as well as alternate data streams
+    if relative_path.contains(':') {
+        return Err(GrufError::unsafe_path("Path contains a drive letter"));
+    }
+    for component in relative_path.split(&['\\', '/'][..]) {
+        // Windows ignores trailing dots and spaces, so ". ." is the same as "."
+        if !component.is_empty() && component.trim_end_matches(&['.', ' '][..]).is_empty() {
+            return Err(GrufError::unsafe_path(
+                "Path contains a traversal component",
+            ));
+        }
+        // Device names are reserved whatever their extension is
+        let stem = component
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .trim_end()
+            .to_ascii_uppercase();
+        let is_reserved_name = RESERVED_NAMES.contains(&stem.as_str())
+            || RESERVED_NUMBERED_NAMES.iter().any(|name| {
+                stem.len() == name.len() + 1
+                    && stem.starts_with(name)
+                    && stem.ends_with(|c: char| c.is_ascii_digit() && c != '0')
+            });
+        if is_reserved_name {
+            return Err(GrufError::unsafe_path(
+                "Path contains a reserved device name",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a patch's file name is a single plain path component, which
+/// can be safely joined to the directory patches are downloaded into.
+pub fn check_file_name(file_name: &str) -> Result<()> {
+    if file_name.contains(&['\\', '/'][..]) {
+        return Err(GrufError::unsafe_path(
+            "File name contains a path separator",
+        ));
+    }
+    check_relative_path(file_name)
+}
+
 fn parse_data_integrity_info(data: &str) -> HashMap<&str, u32> {
     let vec_lines: Vec<_> = data.lines().collect();
     vec_lines
@@ -189,6 +342,87 @@ impl<R: Read + Seek> ThorArchive<R> {
         }
         Ok(true)
     }
+
+    /// Checks if the container has been signed with one of the given Ed25519
+    /// public keys and hasn't been modified since
+    pub fn is_signed_by<I, K>(&mut self, public_keys: I) -> Result<bool>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let signature_data = self.read_file_content(SIGNATURE_FILE_NAME)?;
+        if signature_data.len() < SIGNATURE_LENGTH {
+            return Err(GrufError::parsing_error("Signature is truncated"));
+        }
+        let (signature, manifest) = signature_data.split_at(SIGNATURE_LENGTH);
+        let signature = Signature::from_bytes(signature)
+            .map_err(|_| GrufError::parsing_error("Invalid signature"))?;
+        let mut is_signature_trusted = false;
+        for public_key in public_keys {
+            let public_key = PublicKey::from_bytes(public_key.as_ref())
+                .map_err(|_| GrufError::parsing_error("Invalid public key"))?;
+            if public_key.verify(manifest, &signature).is_ok() {
+                is_signature_trusted = true;
+                break;
+            }
+        }
+        if !is_signature_trusted {
+            return Ok(false);
+        }
+
+        // The signature is trusted, now make sure the content matches
+        let manifest_as_str = string_from_win_1252(manifest)?;
+        let signed_info = parse_data_signature_manifest(manifest_as_str.as_str());
+        let expected_header = [
+            (
+                "use_grf_merging",
+                if self.use_grf_merging() { "1" } else { "0" },
+            ),
+            (
+                "target_grf_name",
+                self.container.header.target_grf_name.as_str(),
+            ),
+        ];
+        for (key, value) in expected_header.iter() {
+            if signed_info.get(key) != Some(value) {
+                return Ok(false);
+            }
+        }
+        let file_entries: Vec<ThorFileEntry> = self
+            .get_entries()
+            .filter(|e| !e.is_internal())
+            .cloned()
+            .collect();
+        if file_entries.len() + expected_header.len() != signed_info.len() {
+            return Ok(false);
+        }
+        for file_entry in file_entries {
+            let signed_value = match signed_info.get(file_entry.relative_path.as_str()) {
+                Some(v) => *v,
+                None => return Ok(false),
+            };
+            if file_entry.is_removed {
+                if signed_value != "removed" {
+                    return Ok(false);
+                }
+                continue;
+            }
+            let file_content = self.read_file_content(&file_entry.relative_path)?;
+            if hex::encode(&Sha256::digest(file_content.as_slice())[..]) != signed_value {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn parse_data_signature_manifest(data: &str) -> HashMap<&str, &str> {
+    data.lines()
+        .filter_map(|line| {
+            let separator_pos = line.rfind('=')?;
+            Some((&line[..separator_pos], &line[separator_pos + 1..]))
+        })
+        .collect()
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -234,7 +468,7 @@ pub struct ThorFileEntry {
 
 impl ThorFileEntry {
     pub fn is_internal(&self) -> bool {
-        self.relative_path == INTEGRITY_FILE_NAME
+        self.relative_path == INTEGRITY_FILE_NAME || self.relative_path == SIGNATURE_FILE_NAME
     }
 }
 
@@ -475,6 +709,155 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_patch_list_from_string_is_lenient() {
+        // Lines accepted by older versions of the patcher are still accepted
+        let plist_content = "1 patch1.thor // note
+2 patch2.thor
+2 patch3.thor
+3 patch2.thor
+foo patch4.thor
+5";
+        let patches: Vec<(usize, String)> = patch_list_from_string(plist_content)
+            .into_iter()
+            .map(|patch| (patch.index, patch.file_name))
+            .collect();
+        assert_eq!(
+            patches,
+            vec![
+                (1, "patch1.thor".to_string()),
+                (2, "patch2.thor".to_string()),
+                (2, "patch3.thor".to_string()),
+                (3, "patch2.thor".to_string()),
+            ]
+        );
+        let (_, diagnostics) =
+            parse_patch_list(plist_content, PatchListParsingMode::Lenient).unwrap();
+        let line_numbers: Vec<usize> = diagnostics.iter().map(|d| d.line_number).collect();
+        assert_eq!(line_numbers, vec![1, 3, 4, 5, 6]);
+        assert!(parse_patch_list(plist_content, PatchListParsingMode::Strict).is_err());
+    }
+
+    #[test]
+    fn test_parse_patch_list() {
+        let plist_content = "# Comment
+
+1 patch1.thor
+12a patch2.thor
+3
+4 patch4.thor extra
+1 patch5.thor
+6 patch1.thor
+  // Indented comment
+7 patch7.thor\r
+";
+        let (patch_list, diagnostics) =
+            parse_patch_list(plist_content, PatchListParsingMode::Lenient).unwrap();
+        let patches: Vec<(usize, &str)> = patch_list
+            .iter()
+            .map(|patch| (patch.index, patch.file_name.as_str()))
+            .collect();
+        assert_eq!(
+            patches,
+            vec![
+                (1, "patch1.thor"),
+                (1, "patch5.thor"),
+                (4, "patch4.thor"),
+                (6, "patch1.thor"),
+                (7, "patch7.thor"),
+            ]
+        );
+        let line_numbers: Vec<usize> = diagnostics.iter().map(|d| d.line_number).collect();
+        assert_eq!(line_numbers, vec![4, 5, 6, 7, 8]);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "line 4: invalid patch index '12a'"
+        );
+
+        let err = parse_patch_list(plist_content, PatchListParsingMode::Strict).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("line 8: duplicate file name 'patch1.thor'"));
+        assert!(parse_patch_list("1 patch1.thor\n", PatchListParsingMode::Strict).is_ok());
+    }
+
+    #[test]
+    fn test_check_file_name() {
+        for file_name in ["patch1.thor", "2021-05-07_data..thor"].iter() {
+            assert!(check_file_name(file_name).is_ok(), "{}", file_name);
+        }
+        let unsafe_file_names = [
+            "",
+            "..",
+            "../../game.exe",
+            "..\\game.exe",
+            "data/patch.thor",
+            "/tmp/patch.thor",
+            "C:\\game.exe",
+            "C:patch.thor",
+            "patch.thor:stream",
+            "CON.thor",
+        ];
+        for file_name in unsafe_file_names.iter() {
+            assert!(
+                matches!(check_file_name(file_name), Err(GrufError::UnsafePath(_))),
+                "{}",
+                file_name
+            );
+        }
+
+        let plist_content = "1 patch1.thor\n2 ../../game.exe\n3 C:\\game.exe\n";
+        let (patch_list, diagnostics) =
+            parse_patch_list(plist_content, PatchListParsingMode::Lenient).unwrap();
+        assert_eq!(
+            vec![1],
+            patch_list.iter().map(|p| p.index).collect::<Vec<_>>()
+        );
+        let line_numbers: Vec<usize> = diagnostics.iter().map(|d| d.line_number).collect();
+        assert_eq!(line_numbers, vec![2, 3]);
+        assert!(parse_patch_list(plist_content, PatchListParsingMode::Strict).is_err());
+    }
+
+    #[test]
+    fn test_check_relative_path() {
+        let safe_paths = [
+            "client.exe",
+            "data\\texture\\icon_num.bmp",
+            "savedata/OptionInfo.lua",
+            "data\\..name\\file..txt",
+            "console.txt",
+            "com10.dll",
+        ];
+        for path in safe_paths.iter() {
+            assert!(check_relative_path(path).is_ok(), "{}", path);
+        }
+        let unsafe_paths = [
+            "",
+            "..\\..\\Windows\\evil.dll",
+            "data\\..\\..\\evil.dll",
+            "data/../../evil.dll",
+            "data\\. .\\evil.dll",
+            "C:\\Windows\\evil.dll",
+            "c:evil.dll",
+            "\\Windows\\evil.dll",
+            "\\\\server\\share\\evil.dll",
+            "/etc/passwd",
+            "data\\evil.dll:stream",
+            "data\\CON",
+            "nul.txt",
+            "data\\com1.dll",
+            "LPT9",
+            "data\\evil\0.dll",
+        ];
+        for path in unsafe_paths.iter() {
+            assert!(
+                matches!(check_relative_path(path), Err(GrufError::UnsafePath(_))),
+                "{}",
+                path
+            );
+        }
+    }
+
     #[test]
     fn test_open_empty_container() {
         let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
@@ -1,6 +1,6 @@
 mod patch_definition;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::{env, process};
 
@@ -37,6 +37,13 @@ struct Opt {
         help = "Path to the output archive (default: <patch_definition_file_name>.thor)"
     )]
     output_file: Option<PathBuf>,
+    #[structopt(
+        parse(from_os_str),
+        short,
+        long,
+        help = "Path to a file containing a hex-encoded Ed25519 secret key used to sign the patch"
+    )]
+    signing_key_file: Option<PathBuf>,
 }
 
 fn run(cli_args: Opt) -> Result<()> {
@@ -58,6 +65,12 @@ fn run(cli_args: Opt) -> Result<()> {
     );
     let patch_definition = parse_patch_definition(&cli_args.patch_definition_file)
         .context("Failed to parse the patch definition")?;
+    let signing_key = match &cli_args.signing_key_file {
+        Some(signing_key_file) => {
+            Some(read_signing_key(signing_key_file).context("Failed to read the signing key")?)
+        }
+        None => None,
+    };
 
     // Display patch info
     log::info!("GRF merging: {}", patch_definition.use_grf_merging);
@@ -69,8 +82,13 @@ fn run(cli_args: Opt) -> Result<()> {
     }
 
     // Generate THOR archive
-    generate_patch_from_definition(patch_definition, patch_data_directory, &output_file_path)
-        .context("Failed to generate patch from definition")?;
+    generate_patch_from_definition(
+        patch_definition,
+        patch_data_directory,
+        &output_file_path,
+        signing_key,
+    )
+    .context("Failed to generate patch from definition")?;
     log::info!(
         "Patch generated at '{}'",
         output_file_path.to_string_lossy()
@@ -82,6 +100,7 @@ fn generate_patch_from_definition<P1, P2>(
     patch_definition: PatchDefinition,
     patch_data_directory: P1,
     output_path: P2,
+    signing_key: Option<Vec<u8>>,
 ) -> Result<()>
 where
     P1: AsRef<Path>,
@@ -94,6 +113,10 @@ where
         patch_definition.target_grf_name,
         patch_definition.include_checksums,
     )?;
+    if let Some(signing_key) = signing_key {
+        let public_key = archive_builder.sign_with(&signing_key)?;
+        log::info!("Signed with public key: {}", hex::encode(public_key));
+    }
     for entry in patch_definition.entries {
         let win32_relative_path = win32_path(&entry.relative_path);
         let target_win32_relative_path = entry.in_grf_path.unwrap_or(win32_relative_path.clone());
@@ -155,6 +178,12 @@ where
     Ok(())
 }
 
+/// Reads a hex-encoded Ed25519 secret key from a file.
+fn read_signing_key(signing_key_file: impl AsRef<Path>) -> Result<Vec<u8>> {
+    let content = fs::read_to_string(signing_key_file)?;
+    Ok(hex::decode(content.trim())?)
+}
+
 fn main() {
     const SUCCESS_EXIT_CODE: i32 = 0;
     const FAILURE_EXIT_CODE: i32 = 1;
@@ -0,0 +1,191 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use gruf::thor::ThorPatchInfo;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PatcherCache {
+    #[serde(default)]
+    pub applied_patches: Vec<AppliedPatch>, // Ledger of applied patches, oldest first
+    #[serde(default, skip_serializing)]
+    pub last_patch_index: Option<usize>, // Written by older versions of the patcher
+    #[serde(default)]
+    pub patch_servers: Vec<PatchServerStats>,
+}
+
+impl PatcherCache {
+    /// Returns true if `patch` has already been applied.
+    ///
+    /// Patches are identified by their file name, as their index changes when
+    /// the patch list is re-indexed. When both the ledger and the patch list
+    /// know the patch's hash, the hashes must match as well.
+    pub fn is_applied(&self, patch: &ThorPatchInfo, sha256: Option<&str>) -> bool {
+        self.applied_patches.iter().any(|applied| {
+            applied.file_name == patch.file_name
+                && match (applied.sha256.as_deref(), sha256) {
+                    (Some(applied_sha256), Some(sha256)) => {
+                        applied_sha256.eq_ignore_ascii_case(sha256)
+                    }
+                    _ => true,
+                }
+        })
+    }
+
+    /// Adds `patches` to the ledger, replacing the entries of patches that
+    /// have been applied again.
+    pub fn record_applied(&mut self, patches: impl IntoIterator<Item = AppliedPatch>) {
+        for patch in patches {
+            self.applied_patches
+                .retain(|applied| applied.file_name != patch.file_name);
+            self.applied_patches.push(patch);
+        }
+    }
+
+    /// Converts the index written by older versions of the patcher into
+    /// entries of the ledger.
+    ///
+    /// Patches of `patch_list` that precede the index are considered applied,
+    /// if the index can be found in `patch_list`.
+    pub fn migrate_last_patch_index(&mut self, patch_list: &[ThorPatchInfo]) {
+        if let Some(last_patch_index) = self.last_patch_index.take() {
+            // First we verify that our cached index looks relevant
+            if patch_list.iter().any(|x| x.index == last_patch_index) {
+                let migrated_patches: Vec<AppliedPatch> = patch_list
+                    .iter()
+                    .filter(|patch| {
+                        patch.index <= last_patch_index && !self.is_applied(patch, None)
+                    })
+                    .map(|patch| AppliedPatch {
+                        index: patch.index,
+                        file_name: patch.file_name.clone(),
+                        sha256: None,
+                        applied_at: 0,
+                        server: None,
+                    })
+                    .collect();
+                self.record_applied(migrated_patches);
+            }
+        }
+    }
+}
+
+/// Entry of the ledger of applied patches.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppliedPatch {
+    pub index: usize,
+    pub file_name: String,
+    pub sha256: Option<String>, // Hex-encoded hash of the THOR archive
+    pub applied_at: u64,        // Seconds since the UNIX epoch (0 if unknown)
+    pub server: Option<String>, // Name of the patch server the patch was downloaded from
+}
+
+/// Measurements made when probing a patch server.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PatchServerStats {
+    pub name: String,
+    pub latency_ms: u64, // Time it took to receive the patch list's headers
+    pub bytes_per_sec: Option<u64>, // Measured when fetching the first patch
+}
+
+pub async fn read_cache_file(cache_file_path: impl AsRef<Path>) -> Result<PatcherCache> {
+    let file = File::open(cache_file_path)?;
+    serde_json::from_reader(file).context("Failed to deserialize patcher cache")
+}
+
+/// Writes the cache file atomically (i.e., the previous version of the file is
+/// kept if writing fails).
+pub async fn write_cache_file(
+    cache_file_path: impl AsRef<Path>,
+    new_cache: &PatcherCache,
+) -> Result<()> {
+    let cache_file_path = cache_file_path.as_ref();
+    let tmp_file_path = cache_file_path.with_extension("dat.tmp");
+    let file = File::create(&tmp_file_path)?;
+    serde_json::to_writer(&file, new_cache).context("Failed to serialize patcher cache")?;
+    file.sync_all()?;
+    fs::rename(&tmp_file_path, cache_file_path).context("Failed to replace patcher cache")
+}
+
+/// Reads the cache file, modifies it with `update` and writes it back.
+///
+/// A default cache is used if the cache file doesn't exist or is invalid.
+pub async fn update_cache_file<F: FnOnce(&mut PatcherCache)>(
+    cache_file_path: impl AsRef<Path>,
+    update: F,
+) -> Result<()> {
+    let mut cache = read_cache_file(&cache_file_path).await.unwrap_or_default();
+    update(&mut cache);
+    write_cache_file(cache_file_path, &cache).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch_info(index: usize, file_name: &str) -> ThorPatchInfo {
+        ThorPatchInfo {
+            index,
+            file_name: file_name.to_string(),
+        }
+    }
+
+    fn applied_patch(index: usize, file_name: &str, sha256: Option<&str>) -> AppliedPatch {
+        AppliedPatch {
+            index,
+            file_name: file_name.to_string(),
+            sha256: sha256.map(|s| s.to_string()),
+            applied_at: 0,
+            server: None,
+        }
+    }
+
+    #[test]
+    fn test_migrate_last_patch_index() {
+        let patch_list = vec![
+            patch_info(1, "patch1.thor"),
+            patch_info(2, "patch2.thor"),
+            patch_info(3, "patch3.thor"),
+        ];
+        let mut cache: PatcherCache = serde_json::from_str(r#"{"last_patch_index":2}"#).unwrap();
+        cache.migrate_last_patch_index(&patch_list);
+        assert!(cache.is_applied(&patch_list[0], None));
+        assert!(cache.is_applied(&patch_list[1], None));
+        assert!(!cache.is_applied(&patch_list[2], None));
+        // The index isn't written anymore
+        assert!(!serde_json::to_string(&cache)
+            .unwrap()
+            .contains("last_patch_index"));
+
+        // Irrelevant indices are ignored
+        let mut cache: PatcherCache = serde_json::from_str(r#"{"last_patch_index":5}"#).unwrap();
+        cache.migrate_last_patch_index(&patch_list);
+        assert!(cache.applied_patches.is_empty());
+        // Patches inserted below applied patches are still pending
+        cache
+            .applied_patches
+            .push(applied_patch(3, "patch3.thor", None));
+        assert!(!cache.is_applied(&patch_list[1], None));
+    }
+
+    #[test]
+    fn test_is_applied() {
+        let mut cache = PatcherCache::default();
+        cache.record_applied(vec![
+            applied_patch(1, "patch1.thor", Some("aa")),
+            applied_patch(2, "patch2.thor", None),
+        ]);
+        // Re-indexed patch lists don't invalidate the ledger
+        assert!(cache.is_applied(&patch_info(10, "patch1.thor"), None));
+        assert!(cache.is_applied(&patch_info(20, "patch2.thor"), Some("bb")));
+        assert!(!cache.is_applied(&patch_info(3, "patch3.thor"), None));
+        // Patches whose content changed must be applied again
+        assert!(cache.is_applied(&patch_info(1, "patch1.thor"), Some("AA")));
+        assert!(!cache.is_applied(&patch_info(1, "patch1.thor"), Some("bb")));
+
+        cache.record_applied(vec![applied_patch(11, "patch1.thor", Some("bb"))]);
+        assert_eq!(2, cache.applied_patches.len());
+        assert!(cache.is_applied(&patch_info(1, "patch1.thor"), Some("bb")));
+    }
+}
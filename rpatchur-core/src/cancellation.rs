@@ -0,0 +1,26 @@
+use super::PatcherCommand;
+
+pub type InterruptibleFnResult<T> = std::result::Result<T, InterruptibleFnError>;
+
+pub enum InterruptibleFnError {
+    Err(String), // An actual error
+    Interrupted, // An interruption
+}
+
+/// Waits until the patching task is asked to stop.
+///
+/// Other commands received in the meantime are passed to `handle_command`.
+pub async fn wait_for_cancellation<F: FnMut(PatcherCommand)>(
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    mut handle_command: F,
+) -> InterruptibleFnError {
+    loop {
+        match patching_thread_rx.recv_async().await {
+            Err(_) => return InterruptibleFnError::Err("Channel was closed".to_string()),
+            Ok(PatcherCommand::CancelUpdate) | Ok(PatcherCommand::Quit) => {
+                return InterruptibleFnError::Interrupted
+            }
+            Ok(cmd) => handle_command(cmd),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// Settings of the patching engine.
+///
+/// `web`, `client` and `patching` are sections of rpatchur's configuration
+/// file, `paths` tells where the engine keeps its files.
+#[derive(Clone)]
+pub struct EngineConfiguration {
+    pub web: WebConfiguration,
+    pub client: ClientConfiguration,
+    pub patching: PatchingConfiguration,
+    pub paths: EnginePaths,
+}
+
+/// Files and directories used by the patching engine.
+#[derive(Clone, Debug)]
+pub struct EnginePaths {
+    pub game_directory: PathBuf, // Directory of the game client, patches are applied to it
+    pub cache_file: PathBuf,     // Keeps track of the applied patches
+    pub update_lock_file: PathBuf, // Prevents several patchers from updating the game at once
+    pub download_directory: PathBuf, // Used unless patching.download_cache_directory is set
+}
+
+impl EnginePaths {
+    /// Places the engine's files in `game_directory`, named after
+    /// `instance_name` (e.g., `<instance_name>.dat` for the cache file).
+    pub fn new(game_directory: impl Into<PathBuf>, instance_name: impl AsRef<OsStr>) -> Self {
+        let game_directory = game_directory.into();
+        let instance_path = |extension: &str| {
+            game_directory
+                .join(instance_name.as_ref())
+                .with_extension(extension)
+        };
+        Self {
+            cache_file: instance_path("dat"),
+            update_lock_file: instance_path("lock"),
+            download_directory: instance_path("downloads"),
+            game_directory,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct WebConfiguration {
+    pub preferred_patch_server: Option<String>, // Name of the patch server to use in priority
+    pub patch_server_timeout: Option<u64>, // Time (in seconds) after which patch servers are considered unavailable
+    pub strict_patch_list: Option<bool>, // Refuse plist.txt files and manifests containing invalid entries instead of ignoring them
+    pub patcher_update_url: Option<String>, // URL of the document describing the latest release of the patcher
+    pub patch_servers: Vec<PatchServerInfo>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PatchServerInfo {
+    pub name: String,                 // Name of that identifies the patch server
+    pub plist_url: String,            // URL of the plist.txt file
+    pub patch_url: String,            // URL of the directory containing .thor files
+    pub manifest_url: Option<String>, // URL of the JSON/YAML patch manifest (plist.txt is used as a fallback)
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ClientConfiguration {
+    pub default_grf_name: String, // GRF file to patch by default
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PatchingConfiguration {
+    pub in_place: bool,        // In-place GRF patching
+    pub check_integrity: bool, // Check THOR archives' integrity
+    pub create_grf: bool,      // Create new GRFs if they don't exist
+    #[serde(default)]
+    pub trusted_public_keys: Vec<String>, // Hex-encoded Ed25519 keys patches must be signed with
+    pub max_parallel_downloads: Option<usize>, // Maximum number of patches downloaded at once
+    pub download_speed_limit: Option<u64>, // Maximum download speed in bytes per second
+    pub download_cache_directory: Option<String>, // Directory where downloaded patches are kept until they're applied
+    pub download_cache_size_limit: Option<u64>,   // Maximum size of the download cache in bytes
+}
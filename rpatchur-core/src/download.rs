@@ -0,0 +1,763 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::ThorPatchInfo;
+use reqwest::header::{self, HeaderMap};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use super::patching::append_extension;
+
+const PARTIAL_FILE_EXTENSION: &str = "part";
+const VALIDATORS_FILE_EXTENSION: &str = "part.json";
+
+/// Remote server that serves patch files.
+pub struct PatchMirror {
+    pub name: String,
+    pub patch_url: Url,
+}
+
+/// List of mirrors that serve the same patches, in order of preference.
+///
+/// Downloads are spread across all the mirrors that are known to serve the
+/// patches, each download going to the mirror with the fewest downloads in
+/// progress. Mirrors that keep failing are not used anymore. Fallback mirrors
+/// (that couldn't be checked) are only used, one at a time, once all the other
+/// mirrors have failed.
+pub struct PatchMirrors {
+    mirrors: Vec<MirrorState>,
+}
+
+struct MirrorState {
+    mirror: PatchMirror,
+    fallback: bool,
+    failed: AtomicBool,
+    downloads_in_progress: AtomicUsize,
+}
+
+/// Mirror acquired for a download, released when dropped.
+pub struct MirrorLease<'a> {
+    state: &'a MirrorState,
+    mirror_index: usize,
+}
+
+impl<'a> MirrorLease<'a> {
+    pub fn mirror(&self) -> &'a PatchMirror {
+        &self.state.mirror
+    }
+
+    pub fn index(&self) -> usize {
+        self.mirror_index
+    }
+}
+
+impl Drop for MirrorLease<'_> {
+    fn drop(&mut self) {
+        self.state
+            .downloads_in_progress
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl PatchMirrors {
+    pub fn new(mirrors: Vec<PatchMirror>, fallback_mirrors: Vec<PatchMirror>) -> Self {
+        let new_state = |fallback| {
+            move |mirror| MirrorState {
+                mirror,
+                fallback,
+                failed: AtomicBool::new(false),
+                downloads_in_progress: AtomicUsize::new(0),
+            }
+        };
+        Self {
+            mirrors: mirrors
+                .into_iter()
+                .map(new_state(false))
+                .chain(fallback_mirrors.into_iter().map(new_state(true)))
+                .collect(),
+        }
+    }
+
+    /// Returns the mirrors that can currently be used, with their index,
+    /// leaving out the mirrors at `excluded_mirrors`.
+    fn usable_mirrors(&self, excluded_mirrors: &[usize]) -> Vec<(usize, &MirrorState)> {
+        let mut working_mirrors = self
+            .mirrors
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !excluded_mirrors.contains(i))
+            .filter(|(_, state)| !state.failed.load(Ordering::SeqCst));
+        let mirrors: Vec<(usize, &MirrorState)> = working_mirrors
+            .clone()
+            .filter(|(_, state)| !state.fallback)
+            .collect();
+        if mirrors.is_empty() {
+            working_mirrors.next().into_iter().collect()
+        } else {
+            mirrors
+        }
+    }
+
+    /// Acquires the mirror to use for a new download, if any mirror is left.
+    ///
+    /// `excluded_mirrors` contains the indices of the mirrors that have
+    /// already been tried for this download.
+    pub fn acquire(&self, excluded_mirrors: &[usize]) -> Option<MirrorLease<'_>> {
+        let (mirror_index, state) = self
+            .usable_mirrors(excluded_mirrors)
+            .into_iter()
+            .min_by_key(|(_, state)| state.downloads_in_progress.load(Ordering::SeqCst))?;
+        state.downloads_in_progress.fetch_add(1, Ordering::SeqCst);
+        Some(MirrorLease {
+            state,
+            mirror_index,
+        })
+    }
+
+    /// Reports that the mirror at `mirror_index` keeps failing.
+    ///
+    /// Returns true if the mirror wasn't already known to be failing.
+    pub fn report_failure(&self, mirror_index: usize) -> bool {
+        !self.mirrors[mirror_index]
+            .failed
+            .swap(true, Ordering::SeqCst)
+    }
+
+    /// Returns the names of the mirrors that are currently used.
+    pub fn mirrors_in_use(&self) -> Vec<String> {
+        self.usable_mirrors(&[])
+            .into_iter()
+            .map(|(_, state)| state.mirror.name.clone())
+            .collect()
+    }
+}
+
+/// Token bucket shared by all downloads, used to limit the global download
+/// speed.
+///
+/// Downloads consume tokens as they receive data and wait when the bucket
+/// runs dry. The limit can be changed at any time.
+pub struct DownloadRateLimiter {
+    state: Mutex<RateLimiterState>,
+}
+
+struct RateLimiterState {
+    bytes_per_sec: Option<u64>, // None when unlimited
+    available_bytes: f64,       // Negative when downloads are ahead of the limit
+    last_refill: Instant,
+}
+
+impl DownloadRateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(RateLimiterState {
+                bytes_per_sec: bytes_per_sec.filter(|&limit| limit > 0),
+                available_bytes: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Changes the limit, `None` (or 0) meaning unlimited.
+    pub fn set_limit(&self, bytes_per_sec: Option<u64>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.bytes_per_sec = bytes_per_sec.filter(|&limit| limit > 0);
+            state.available_bytes = 0.0;
+            state.last_refill = Instant::now();
+        }
+    }
+
+    /// Takes `byte_count` tokens from the bucket, waiting if the limit has been
+    /// exceeded.
+    pub async fn consume(&self, byte_count: u64) {
+        let wait_time = match self.state.lock() {
+            Err(_) => return,
+            Ok(mut state) => {
+                let bytes_per_sec = match state.bytes_per_sec {
+                    None => return,
+                    Some(bytes_per_sec) => bytes_per_sec as f64,
+                };
+                // Refill the bucket, allowing bursts of at most one second
+                let now = Instant::now();
+                let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+                state.available_bytes =
+                    (state.available_bytes + elapsed * bytes_per_sec).min(bytes_per_sec);
+                state.last_refill = now;
+                state.available_bytes -= byte_count as f64;
+                if state.available_bytes >= 0.0 {
+                    return;
+                }
+                Duration::from_secs_f64(-state.available_bytes / bytes_per_sec)
+            }
+        };
+        tokio::time::sleep(wait_time).await;
+    }
+}
+
+/// Error that's likely to go away if the operation that caused it is retried.
+#[derive(Debug)]
+pub struct TransientError(pub String);
+
+impl fmt::Display for TransientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+/// Error caused by a mirror that doesn't serve a file at the moment, which
+/// other mirrors might serve.
+#[derive(Debug)]
+pub struct MirrorError(pub String);
+
+impl fmt::Display for MirrorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for MirrorError {}
+
+/// Returns true if `error` was caused by a network issue or by the remote
+/// server and is worth retrying.
+pub fn is_transient_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if cause.is::<TransientError>() {
+            true
+        } else if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            e.is_timeout() || e.is_connect() || e.is_request() || e.is_body()
+        } else if let Some(e) = cause.downcast_ref::<io::Error>() {
+            matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::UnexpectedEof
+            )
+        } else {
+            false
+        }
+    })
+}
+
+/// Returns true if a response with the given status code means that the
+/// mirror doesn't serve the requested file at the moment.
+fn is_mirror_error_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::FORBIDDEN
+            | StatusCode::NOT_FOUND
+            | StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+    )
+}
+
+/// Returns true if `error` is specific to the mirror a file was requested
+/// from, in which case the file should be requested from another mirror.
+pub fn is_mirror_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<MirrorError>())
+}
+
+/// Validators sent by the server along with a file, used to make sure a remote
+/// file hasn't changed before resuming its download.
+#[derive(Serialize, Deserialize)]
+struct DownloadValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl DownloadValidators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let get_header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        Self {
+            etag: get_header(header::ETAG),
+            last_modified: get_header(header::LAST_MODIFIED),
+        }
+    }
+
+    /// Returns the value to use in the 'If-Range' header of a request, if any.
+    ///
+    /// Weak entity tags cannot be used with 'If-Range'.
+    fn if_range(&self) -> Option<&str> {
+        match &self.etag {
+            Some(etag) if !etag.starts_with("W/") => Some(etag.as_str()),
+            _ => self.last_modified.as_deref(),
+        }
+    }
+}
+
+/// Returns the names of the files a download of `file_name` can leave behind.
+pub fn download_file_names(file_name: &str) -> Vec<String> {
+    vec![
+        file_name.to_string(),
+        format!("{}.{}", file_name, PARTIAL_FILE_EXTENSION),
+        format!("{}.{}", file_name, VALIDATORS_FILE_EXTENSION),
+    ]
+}
+
+/// Downloads a patch from `patch_file_url` into `download_directory` and
+/// returns the path of the downloaded file.
+///
+/// Patches are first downloaded into a partial file which is kept when a
+/// download fails. Subsequent downloads of the same patch resume from where
+/// the previous one stopped, provided the remote file hasn't changed.
+///
+/// `progress_callback` receives the number of bytes of the file that have been
+/// downloaded, including the ones of the download that's resumed, as well as
+/// the size of the file (0 if unknown).
+pub async fn download_patch_to_directory<CB: FnMut(u64, u64)>(
+    client: &reqwest::Client,
+    patch_file_url: &Url,
+    patch: &ThorPatchInfo,
+    download_directory: impl AsRef<Path>,
+    rate_limiter: &DownloadRateLimiter,
+    mut progress_callback: CB,
+) -> Result<PathBuf> {
+    gruf::thor::check_file_name(&patch.file_name)
+        .with_context(|| format!("Invalid file name '{}'", patch.file_name))?;
+    let file_path = download_directory.as_ref().join(patch.file_name.as_str());
+    let partial_file_path = append_extension(&file_path, PARTIAL_FILE_EXTENSION);
+    let validators_file_path = append_extension(&file_path, VALIDATORS_FILE_EXTENSION);
+
+    // Check if there's a download to resume
+    let mut resume_from = match fs::metadata(&partial_file_path).await {
+        Ok(metadata) if metadata.len() > 0 => read_validators_file(&validators_file_path)
+            .await
+            .map(|validators| (metadata.len(), validators)),
+        _ => None,
+    };
+    let mut request = client.get(patch_file_url.clone());
+    if let Some((offset, validators)) = &resume_from {
+        if let Some(if_range) = validators.if_range() {
+            log::info!("Resuming download of '{}'", patch.file_name);
+            request = request
+                .header(header::RANGE, format!("bytes={}-", offset))
+                .header(header::IF_RANGE, if_range);
+        }
+    }
+    let mut resp = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to download file '{}'", patch.file_name))?;
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE && resume_from.is_some() {
+        // The partial file is complete or doesn't match the remote file
+        // anymore, download the whole file again
+        log::info!("Cannot resume download of '{}'", patch.file_name);
+        let _ = fs::remove_file(&partial_file_path).await;
+        let _ = fs::remove_file(&validators_file_path).await;
+        resume_from = None;
+        resp = client
+            .get(patch_file_url.clone())
+            .send()
+            .await
+            .with_context(|| format!("Failed to download file '{}'", patch.file_name))?;
+    }
+
+    let status = resp.status();
+    let (mut file, expected_file_size, resumed_bytes) = match resume_from {
+        Some((offset, _)) if status == StatusCode::PARTIAL_CONTENT => {
+            let file_size = parse_content_range(resp.headers(), offset).with_context(|| {
+                format!("Invalid partial response for file '{}'", patch.file_name)
+            })?;
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&partial_file_path)
+                .await
+                .with_context(|| format!("Failed to open '{}'", partial_file_path.display()))?;
+            (file, file_size, offset)
+        }
+        _ if status.is_success() => {
+            // Download the whole file, the remote file might have changed
+            let file = File::create(&partial_file_path)
+                .await
+                .with_context(|| format!("Failed to create '{}'", partial_file_path.display()))?;
+            let validators = DownloadValidators::from_headers(resp.headers());
+            if validators.if_range().is_some() {
+                write_validators_file(&validators_file_path, &validators).await?;
+            } else {
+                // Download cannot be resumed safely
+                let _ = fs::remove_file(&validators_file_path).await;
+            }
+            (file, resp.content_length(), 0)
+        }
+        _ if status.is_server_error() => {
+            return Err(TransientError(format!(
+                "Server failed to serve '{}' ({})",
+                patch.file_name, status
+            ))
+            .into());
+        }
+        _ if is_mirror_error_status(status) => {
+            return Err(MirrorError(format!(
+                "Patch file '{}' is not available on the remote server ({})",
+                patch.file_name, status
+            ))
+            .into());
+        }
+        _ => {
+            return Err(anyhow!(
+                "Remote server refused to serve '{}' ({})",
+                patch.file_name,
+                status
+            ));
+        }
+    };
+
+    let mut downloaded_bytes = resumed_bytes;
+    let file_size = expected_file_size
+        .or_else(|| resp.content_length().map(|length| resumed_bytes + length))
+        .unwrap_or(0);
+    progress_callback(downloaded_bytes, file_size);
+    let download_result = async {
+        while let Some(chunk) = resp.chunk().await? {
+            file.write_all(&chunk[..]).await?;
+            downloaded_bytes += chunk.len() as u64;
+            rate_limiter.consume(chunk.len() as u64).await;
+            progress_callback(downloaded_bytes, file_size);
+        }
+        Ok(()) as Result<()>
+    }
+    .await;
+    // Make sure what's been downloaded is written to disk, even on failure, so
+    // that the download can be resumed
+    file.sync_all()
+        .await
+        .with_context(|| format!("Failed to sync downloaded file '{}'", patch.file_name))?;
+    download_result.with_context(|| format!("Failed to download file '{}'", patch.file_name))?;
+    drop(file);
+
+    // Check that the whole file has been received
+    if let Some(expected_file_size) = expected_file_size {
+        let file_size = fs::metadata(&partial_file_path)
+            .await
+            .with_context(|| format!("Failed to stat '{}'", partial_file_path.display()))?
+            .len();
+        if file_size != expected_file_size {
+            return Err(TransientError(format!(
+                "Download of '{}' is incomplete ({} out of {} bytes)",
+                patch.file_name, file_size, expected_file_size
+            ))
+            .into());
+        }
+    }
+
+    fs::rename(&partial_file_path, &file_path)
+        .await
+        .with_context(|| format!("Failed to move '{}'", partial_file_path.display()))?;
+    let _ = fs::remove_file(&validators_file_path).await;
+    Ok(file_path)
+}
+
+/// Parses a 'Content-Range' header and returns the complete file's size.
+///
+/// Fails if the range doesn't start at `expected_offset`.
+fn parse_content_range(headers: &HeaderMap, expected_offset: u64) -> Result<Option<u64>> {
+    let content_range = headers
+        .get(header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .context("Missing 'Content-Range' header")?;
+    let range = content_range
+        .strip_prefix("bytes ")
+        .context("Unsupported range unit")?;
+    let separator = range
+        .find('/')
+        .context("Malformed 'Content-Range' header")?;
+    let (byte_range, complete_length) = (&range[..separator], &range[separator + 1..]);
+    let first_byte: u64 = byte_range
+        .split('-')
+        .next()
+        .and_then(|v| v.parse().ok())
+        .context("Malformed 'Content-Range' header")?;
+    if first_byte != expected_offset {
+        return Err(anyhow!(
+            "Range starts at {} instead of {}",
+            first_byte,
+            expected_offset
+        ));
+    }
+    match complete_length {
+        "*" => Ok(None),
+        v => Ok(Some(v.parse().context("Malformed 'Content-Range' header")?)),
+    }
+}
+
+async fn read_validators_file(
+    validators_file_path: impl AsRef<Path>,
+) -> Option<DownloadValidators> {
+    let content = fs::read(validators_file_path).await.ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+async fn write_validators_file(
+    validators_file_path: impl AsRef<Path>,
+    validators: &DownloadValidators,
+) -> Result<()> {
+    let content = serde_json::to_vec(validators).context("Failed to serialize validators")?;
+    fs::write(validators_file_path, content)
+        .await
+        .context("Failed to write validators file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{all_of, matchers::*, responders::*, Expectation, Server};
+
+    #[tokio::test]
+    async fn test_download_patch_to_directory() {
+        // Generate 200MiB of data
+        let data_size: usize = 200 * 1024 * 1024;
+        let body_content: Vec<u8> = (0..data_size).map(|x| x as u8).collect();
+
+        let patch_name = "patch_archive";
+        let patch_path = format!("/{}", patch_name);
+        // Setup a local web server
+        let server = Server::run();
+        // Configure the server to expect a single GET request and respond
+        // with a 200 status code.
+        server.expect(
+            Expectation::matching(request::method_path("GET", patch_path.clone()))
+                .respond_with(status_code(200).body(body_content.clone())),
+        );
+
+        // "Download" the file
+        let from_url = Url::parse(server.url("/").to_string().as_str()).unwrap();
+        let patch_info = ThorPatchInfo {
+            index: 0,
+            file_name: patch_name.to_string(),
+        };
+        let download_dir = tempfile::tempdir().unwrap();
+        let file_path = download_patch_to_directory(
+            &reqwest::Client::new(),
+            &from_url.join(patch_name).unwrap(),
+            &patch_info,
+            download_dir.path(),
+            &DownloadRateLimiter::new(None),
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+
+        let file_content = std::fs::read(&file_path).unwrap();
+        // Size check
+        assert_eq!(data_size, file_content.len());
+        // Content check
+        assert_eq!(body_content, file_content);
+        // Only the downloaded file should be left
+        assert_eq!(1, std::fs::read_dir(download_dir.path()).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn test_download_patch_to_directory_resume() {
+        let data_size: usize = 1024 * 1024;
+        let body_content: Vec<u8> = (0..data_size).map(|x| x as u8).collect();
+        let resume_offset: usize = 1000;
+
+        let patch_name = "patch_archive";
+        let patch_path = format!("/{}", patch_name);
+        let etag = "\"1234\"";
+        let server = Server::run();
+        // Configure the server to expect a single ranged GET request and
+        // respond with the rest of the file
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", patch_path.clone()),
+                request::headers(contains(("range", "bytes=1000-"))),
+                request::headers(contains(("if-range", etag))),
+            ])
+            .respond_with(
+                status_code(206)
+                    .append_header(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", resume_offset, data_size - 1, data_size),
+                    )
+                    .body(body_content[resume_offset..].to_vec()),
+            ),
+        );
+
+        // Simulate an interrupted download
+        let download_dir = tempfile::tempdir().unwrap();
+        let file_path = download_dir.path().join(patch_name);
+        std::fs::write(
+            append_extension(&file_path, PARTIAL_FILE_EXTENSION),
+            &body_content[..resume_offset],
+        )
+        .unwrap();
+        write_validators_file(
+            append_extension(&file_path, VALIDATORS_FILE_EXTENSION),
+            &DownloadValidators {
+                etag: Some(etag.to_string()),
+                last_modified: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let from_url = Url::parse(server.url("/").to_string().as_str()).unwrap();
+        let patch_info = ThorPatchInfo {
+            index: 0,
+            file_name: patch_name.to_string(),
+        };
+        let mut progress = vec![];
+        let downloaded_file_path = download_patch_to_directory(
+            &reqwest::Client::new(),
+            &from_url.join(patch_name).unwrap(),
+            &patch_info,
+            download_dir.path(),
+            &DownloadRateLimiter::new(None),
+            |dl_now, dl_total| progress.push((dl_now, dl_total)),
+        )
+        .await
+        .unwrap();
+
+        // Progress is reported for the whole file
+        assert_eq!(
+            Some(&(resume_offset as u64, data_size as u64)),
+            progress.first()
+        );
+        assert_eq!(Some(&(data_size as u64, data_size as u64)), progress.last());
+        assert_eq!(file_path, downloaded_file_path);
+        assert_eq!(body_content, std::fs::read(&file_path).unwrap());
+        assert_eq!(1, std::fs::read_dir(download_dir.path()).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn test_download_patch_to_directory_range_not_satisfiable() {
+        let body_content: Vec<u8> = (0..1000).map(|x| x as u8).collect();
+        let patch_name = "patch_archive";
+        let patch_path = format!("/{}", patch_name);
+        let server = Server::run();
+        // The partial file is as big as the remote file
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", patch_path.clone()),
+                request::headers(contains(("range", "bytes=1000-"))),
+            ])
+            .respond_with(status_code(416)),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", patch_path.clone()),
+                request::headers(not(contains(key("range")))),
+            ])
+            .respond_with(status_code(200).body(body_content.clone())),
+        );
+
+        let download_dir = tempfile::tempdir().unwrap();
+        let file_path = download_dir.path().join(patch_name);
+        std::fs::write(
+            append_extension(&file_path, PARTIAL_FILE_EXTENSION),
+            vec![0; body_content.len()],
+        )
+        .unwrap();
+        write_validators_file(
+            append_extension(&file_path, VALIDATORS_FILE_EXTENSION),
+            &DownloadValidators {
+                etag: Some("\"1234\"".to_string()),
+                last_modified: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let from_url = Url::parse(server.url("/").to_string().as_str()).unwrap();
+        let patch_info = ThorPatchInfo {
+            index: 0,
+            file_name: patch_name.to_string(),
+        };
+        let downloaded_file_path = download_patch_to_directory(
+            &reqwest::Client::new(),
+            &from_url.join(patch_name).unwrap(),
+            &patch_info,
+            download_dir.path(),
+            &DownloadRateLimiter::new(None),
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(body_content, std::fs::read(&downloaded_file_path).unwrap());
+        assert_eq!(1, std::fs::read_dir(download_dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_patch_mirrors() {
+        let mirror = |name: &str| PatchMirror {
+            name: name.to_string(),
+            patch_url: Url::parse(&format!("http://{}.example.com/", name)).unwrap(),
+        };
+        let mirrors = PatchMirrors::new(
+            vec![mirror("first"), mirror("second")],
+            vec![mirror("fallback1"), mirror("fallback2")],
+        );
+        assert_eq!(vec!["first", "second"], mirrors.mirrors_in_use());
+        // Downloads are spread across mirrors
+        let first_lease = mirrors.acquire(&[]).unwrap();
+        let second_lease = mirrors.acquire(&[]).unwrap();
+        assert_eq!("first", first_lease.mirror().name);
+        assert_eq!("second", second_lease.mirror().name);
+        drop(first_lease);
+        assert_eq!("first", mirrors.acquire(&[]).unwrap().mirror().name);
+        // Mirrors that have already been tried are left out
+        assert_eq!("second", mirrors.acquire(&[0]).unwrap().mirror().name);
+        assert_eq!("fallback1", mirrors.acquire(&[0, 1]).unwrap().mirror().name);
+
+        // Concurrent reports only count once
+        assert!(mirrors.report_failure(second_lease.index()));
+        assert!(!mirrors.report_failure(second_lease.index()));
+        assert_eq!(vec!["first"], mirrors.mirrors_in_use());
+        // Fallbacks are used one at a time
+        assert!(mirrors.report_failure(0));
+        assert_eq!(vec!["fallback1"], mirrors.mirrors_in_use());
+        assert!(mirrors.report_failure(2));
+        assert_eq!("fallback2", mirrors.acquire(&[]).unwrap().mirror().name);
+        // No mirror left
+        assert!(mirrors.report_failure(3));
+        assert!(mirrors.acquire(&[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_download_rate_limiter() {
+        let rate_limiter = DownloadRateLimiter::new(Some(100_000));
+        let start_time = Instant::now();
+        for _ in 0..5 {
+            rate_limiter.consume(10_000).await;
+        }
+        assert!(start_time.elapsed() >= Duration::from_millis(450));
+
+        // Unlimited
+        rate_limiter.set_limit(None);
+        let start_time = Instant::now();
+        rate_limiter.consume(1_000_000).await;
+        assert!(start_time.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_is_transient_error() {
+        let transient = anyhow::Error::new(TransientError("corrupt".to_string()));
+        assert!(is_transient_error(&transient.context("Failed to download")));
+        let reset = anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(is_transient_error(&reset));
+        let not_found = anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound));
+        assert!(!is_transient_error(&not_found));
+        assert!(!is_transient_error(&anyhow!("Patch file not found")));
+        let not_served = anyhow::Error::new(MirrorError("not found".to_string()));
+        assert!(!is_transient_error(&not_served));
+        assert!(is_mirror_error(&not_served.context("Failed to download")));
+    }
+}
@@ -0,0 +1,287 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use gruf::thor::{self, ThorPatchInfo};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::download::download_file_names;
+
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// Directory where downloaded patches are kept until they've been applied.
+///
+/// Finished downloads are recorded along with their size and SHA-256 hash, so
+/// they can be reused by later updates (after a cancellation or a failure for
+/// example) once they've been verified again.
+pub struct DownloadCache {
+    directory: PathBuf,
+    index: Mutex<DownloadCacheIndex>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct DownloadCacheIndex {
+    entries: Vec<DownloadCacheEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct DownloadCacheEntry {
+    file_name: String,
+    patch_index: usize,
+    size: u64,
+    sha256: String, // Hex-encoded
+    stored_at: u64, // Seconds since the UNIX epoch
+}
+
+impl DownloadCache {
+    /// Opens the download cache located in `directory`, creating it if needed.
+    pub fn open(directory: impl AsRef<Path>) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory).context("Failed to create download directory")?;
+        let mut index: DownloadCacheIndex = File::open(directory.join(INDEX_FILE_NAME))
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default();
+        // File names are joined to the directory to read and remove files
+        index.entries.retain(|entry| {
+            let is_safe = thor::check_file_name(&entry.file_name).is_ok();
+            if !is_safe {
+                log::warn!(
+                    "Ignoring unsafe file name '{}' in the download cache",
+                    entry.file_name
+                );
+            }
+            is_safe
+        });
+        Ok(Self {
+            directory,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Returns the directory patches are downloaded into.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Returns the path of a previous download of `patch`, if it's still
+    /// intact.
+    pub async fn get(&self, patch: &ThorPatchInfo) -> Option<PathBuf> {
+        let entry = self
+            .lock_index()?
+            .entries
+            .iter()
+            .find(|entry| entry.file_name == patch.file_name && entry.patch_index == patch.index)
+            .cloned()?;
+        let file_path = self.directory.join(&entry.file_name);
+        let (size, sha256) = tokio::task::spawn_blocking(move || measure_file(file_path))
+            .await
+            .ok()?
+            .ok()?;
+        if size == entry.size && sha256 == entry.sha256 {
+            Some(self.directory.join(&entry.file_name))
+        } else {
+            log::warn!("Cached download of '{}' is corrupt", patch.file_name);
+            None
+        }
+    }
+
+    /// Records a finished (and verified) download of `patch`.
+    pub async fn insert(&self, patch: &ThorPatchInfo) -> Result<()> {
+        let file_path = self.directory.join(&patch.file_name);
+        let (size, sha256) = tokio::task::spawn_blocking(move || measure_file(file_path))
+            .await
+            .context("Failed to hash downloaded file")??;
+        let stored_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut index = self.lock_index().context("Download cache is poisoned")?;
+        index
+            .entries
+            .retain(|entry| entry.file_name != patch.file_name);
+        index.entries.push(DownloadCacheEntry {
+            file_name: patch.file_name.clone(),
+            patch_index: patch.index,
+            size,
+            sha256,
+            stored_at,
+        });
+        self.write_index(&index)
+    }
+
+    /// Removes the files of the patches that have already been applied (or
+    /// that are not served anymore) and evicts the oldest downloads until the
+    /// cache's size is below `size_limit` (in bytes).
+    pub fn clean_up(
+        &self,
+        patch_list: &[ThorPatchInfo],
+        is_applied: impl Fn(&ThorPatchInfo) -> bool,
+        size_limit: Option<u64>,
+    ) -> Result<()> {
+        let mut index = self.lock_index().context("Download cache is poisoned")?;
+        // Files left by patches that have already been applied
+        for patch in patch_list.iter().filter(|patch| is_applied(patch)) {
+            self.remove_files(&patch.file_name);
+        }
+        // Entries of files that have been applied or removed
+        let directory = &self.directory;
+        index.entries.retain(|entry| {
+            let is_pending = patch_list.iter().any(|patch| {
+                patch.file_name == entry.file_name
+                    && patch.index == entry.patch_index
+                    && !is_applied(patch)
+            });
+            if is_pending {
+                directory.join(&entry.file_name).is_file()
+            } else {
+                let _ = fs::remove_file(directory.join(&entry.file_name));
+                false
+            }
+        });
+
+        // Evict the oldest downloads if needed
+        if let Some(size_limit) = size_limit {
+            index.entries.sort_by_key(|entry| entry.stored_at);
+            let mut cache_size: u64 = index.entries.iter().map(|entry| entry.size).sum();
+            while cache_size > size_limit && !index.entries.is_empty() {
+                let entry = index.entries.remove(0);
+                log::info!("Evicting '{}' from the download cache", entry.file_name);
+                self.remove_files(&entry.file_name);
+                cache_size -= entry.size;
+            }
+        }
+        self.write_index(&index)
+    }
+
+    /// Removes the cache's directory if it doesn't contain any download.
+    pub fn remove_if_empty(&self) {
+        if self.is_empty() {
+            let _ = fs::remove_file(self.directory.join(INDEX_FILE_NAME));
+            let _ = fs::remove_dir(&self.directory);
+        }
+    }
+
+    /// Returns true if the cache doesn't contain any download.
+    pub fn is_empty(&self) -> bool {
+        fs::read_dir(&self.directory)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .all(|entry| entry.file_name() == INDEX_FILE_NAME)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Removes a downloaded file as well as the files of its partial download.
+    fn remove_files(&self, file_name: &str) {
+        if thor::check_file_name(file_name).is_err() {
+            log::warn!("Refusing to remove unsafe file name '{}'", file_name);
+            return;
+        }
+        for file_name in download_file_names(file_name) {
+            if let Err(e) = fs::remove_file(self.directory.join(&file_name)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    log::warn!("Failed to remove '{}': {}.", file_name, e);
+                }
+            }
+        }
+    }
+
+    fn lock_index(&self) -> Option<std::sync::MutexGuard<'_, DownloadCacheIndex>> {
+        self.index.lock().ok()
+    }
+
+    /// Writes the index atomically, so that an interruption cannot corrupt it.
+    fn write_index(&self, index: &DownloadCacheIndex) -> Result<()> {
+        let index_file_path = self.directory.join(INDEX_FILE_NAME);
+        let tmp_file_path = index_file_path.with_extension("json.tmp");
+        let tmp_file = File::create(&tmp_file_path).context("Failed to create index file")?;
+        serde_json::to_writer(&tmp_file, index).context("Failed to serialize index")?;
+        tmp_file.sync_all().context("Failed to sync index file")?;
+        fs::rename(&tmp_file_path, &index_file_path).context("Failed to write index file")
+    }
+}
+
+/// Returns the size and the hex-encoded SHA-256 hash of a file.
+pub fn measure_file(file_path: impl AsRef<Path>) -> Result<(u64, String)> {
+    let mut file = File::open(file_path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch_info(index: usize, file_name: &str) -> ThorPatchInfo {
+        ThorPatchInfo {
+            index,
+            file_name: file_name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let patch_list = vec![
+            patch_info(1, "patch1.thor"),
+            patch_info(2, "patch2.thor"),
+            patch_info(3, "patch3.thor"),
+        ];
+        {
+            let cache = DownloadCache::open(cache_dir.path()).unwrap();
+            for (patch, content) in patch_list.iter().zip(&[&b"1"[..], b"22", b"333"]) {
+                fs::write(cache.directory().join(&patch.file_name), content).unwrap();
+                cache.insert(patch).await.unwrap();
+            }
+        }
+
+        // Downloads are reused by later runs
+        let cache = DownloadCache::open(cache_dir.path()).unwrap();
+        assert!(cache.get(&patch_list[1]).await.is_some());
+        assert!(cache.get(&patch_info(4, "patch2.thor")).await.is_none());
+        // Corrupt downloads are not
+        fs::write(cache.directory().join("patch3.thor"), b"334").unwrap();
+        assert!(cache.get(&patch_list[2]).await.is_none());
+
+        // Applied patches are removed
+        cache.clean_up(&patch_list, |p| p.index <= 1, None).unwrap();
+        assert!(!cache.directory().join("patch1.thor").exists());
+        assert!(cache.get(&patch_list[1]).await.is_some());
+        // Oldest downloads are evicted first
+        cache
+            .clean_up(&patch_list, |p| p.index <= 1, Some(3))
+            .unwrap();
+        assert!(!cache.directory().join("patch2.thor").exists());
+        assert!(cache.directory().join("patch3.thor").exists());
+        cache.clean_up(&patch_list, |p| p.index <= 3, None).unwrap();
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_download_cache_unsafe_file_names() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let cache_dir = root_dir.path().join("downloads");
+        let game_file_path = root_dir.path().join("game.exe");
+        fs::write(&game_file_path, b"game").unwrap();
+        fs::create_dir(&cache_dir).unwrap();
+        fs::write(
+            cache_dir.join(INDEX_FILE_NAME),
+            r#"{"entries": [{"file_name": "../game.exe", "patch_index": 1, "size": 4,
+                "sha256": "", "stored_at": 0}]}"#,
+        )
+        .unwrap();
+
+        let cache = DownloadCache::open(&cache_dir).unwrap();
+        let patch = patch_info(1, "../game.exe");
+        assert!(cache.get(&patch).await.is_none());
+        cache.clean_up(&[patch], |_| true, Some(0)).unwrap();
+        assert!(game_file_path.exists());
+    }
+}
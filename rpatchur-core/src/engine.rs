@@ -0,0 +1,1945 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use advisory_lock::{AdvisoryFileLock, FileLockMode};
+use anyhow::{anyhow, Context, Result};
+use futures::executor::block_on;
+use futures::stream::StreamExt;
+use gruf::thor::{self, PatchListParsingMode, ThorArchive, ThorPatchInfo, ThorPatchList};
+use gruf::GrufError;
+use url::Url;
+
+use super::cache::{
+    read_cache_file, update_cache_file, write_cache_file, AppliedPatch, PatchServerStats,
+};
+use super::cancellation::{wait_for_cancellation, InterruptibleFnError, InterruptibleFnResult};
+use super::config::{EngineConfiguration, PatchServerInfo};
+use super::download::{
+    download_patch_to_directory, is_mirror_error, is_transient_error, DownloadRateLimiter,
+    PatchMirror, PatchMirrors, TransientError,
+};
+use super::download_cache::{measure_file, DownloadCache};
+use super::events::{
+    channels, DownloadProgress, ErrorCode, EventSender, PatchListWarning, PatcherError,
+    PatcherEvent, PatcherEventStream, PatcherHandle,
+};
+use super::manifest::{PatchManifest, PatchManifestEntry};
+use super::patching::{apply_patches_to_disk, apply_patches_to_grf, GrfPatchingMethod};
+use super::self_update::{
+    download_patcher_release, fetch_patcher_release, replace_exe, PatcherRelease,
+};
+use super::PatcherCommand;
+
+/// Time after which patch servers that haven't answered are considered
+/// unavailable
+const DEFAULT_PATCH_SERVER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of patches downloaded at once, unless configured otherwise
+const DEFAULT_MAX_PARALLEL_DOWNLOADS: usize = 32;
+
+/// Representation of a pending patch (a patch that's been downloaded but has
+/// not been applied yet).
+#[derive(Debug)]
+struct PendingPatch {
+    info: thor::ThorPatchInfo,
+    local_file_path: PathBuf,
+    server: Option<String>, // Patch server it was downloaded from (None if cached)
+}
+
+/// Representation of a downloaded patch that's been opened and is ready to be
+/// applied.
+struct OpenedPatch {
+    info: thor::ThorPatchInfo,
+    local_file_path: PathBuf,
+    server: Option<String>,
+    archive: ThorArchive<std::fs::File>,
+    target: PatchTarget,
+}
+
+/// State shared by all the downloads of an update.
+struct DownloadContext<'a> {
+    client: reqwest::Client,
+    mirrors: PatchMirrors,
+    manifest: PatchManifest, // Details of the patches (size, hash, URL, ...)
+    download_cache: &'a DownloadCache,
+    ensure_integrity: bool, // Check downloaded archives' integrity
+    rate_limiter: &'a DownloadRateLimiter,
+    events: &'a EventSender,
+}
+
+/// Result of a successful update.
+enum UpdateOutcome {
+    GameUpdated,              // Patches have been applied
+    PatcherReplaced(PathBuf), // The patcher has been updated and must be restarted from this path
+}
+
+/// Attaches an `ErrorCode` to the errors of a result.
+trait WithErrorCode<T> {
+    fn error_code(self, code: ErrorCode) -> std::result::Result<T, PatcherError>;
+}
+
+impl<T> WithErrorCode<T> for Result<T> {
+    fn error_code(self, code: ErrorCode) -> std::result::Result<T, PatcherError> {
+        self.map_err(|e| PatcherError::new(code, format!("{:#}", e)))
+    }
+}
+
+impl<T> WithErrorCode<T> for InterruptibleFnResult<T> {
+    /// Interruptions are reported with `ErrorCode::Canceled`.
+    fn error_code(self, code: ErrorCode) -> std::result::Result<T, PatcherError> {
+        self.map_err(|e| match e {
+            InterruptibleFnError::Err(msg) => PatcherError::new(code, msg),
+            InterruptibleFnError::Interrupted => {
+                PatcherError::new(ErrorCode::Canceled, "Patching was canceled")
+            }
+        })
+    }
+}
+
+/// Indicates what a patch modifies.
+#[derive(Clone, PartialEq)]
+enum PatchTarget {
+    Grf(PathBuf),       // Path to the GRF file
+    Directory(PathBuf), // Path to the game client's directory
+}
+
+impl PatchTarget {
+    fn of<R: Read + Seek>(
+        thor_archive: &ThorArchive<R>,
+        config: &EngineConfiguration,
+        current_working_dir: impl AsRef<Path>,
+    ) -> Self {
+        if thor_archive.use_grf_merging() {
+            let target_grf_name = {
+                if thor_archive.target_grf_name().is_empty() {
+                    config.client.default_grf_name.clone()
+                } else {
+                    thor_archive.target_grf_name()
+                }
+            };
+            PatchTarget::Grf(current_working_dir.as_ref().join(target_grf_name))
+        } else {
+            PatchTarget::Directory(current_working_dir.as_ref().to_path_buf())
+        }
+    }
+}
+
+/// Patching engine.
+///
+/// The patcher waits for commands sent with its `PatcherHandle` and reports
+/// what it does with `PatcherEvent`s.
+pub struct Patcher {
+    config: EngineConfiguration,
+    commands_rx: flume::Receiver<PatcherCommand>,
+    events: EventSender,
+}
+
+impl Patcher {
+    /// Creates a patcher along with the handle used to control it and the
+    /// stream of events it emits.
+    pub fn new(config: EngineConfiguration) -> (Patcher, PatcherHandle, PatcherEventStream) {
+        let ((handle, commands_rx), (events, event_stream)) = channels();
+        let patcher = Patcher {
+            config,
+            commands_rx,
+            events,
+        };
+        (patcher, handle, event_stream)
+    }
+
+    /// Runs the patcher until it's asked to stop (or until all the handles
+    /// have been dropped).
+    ///
+    /// This waits for a `PatcherCommand::StartUpdate` command before starting
+    /// an interruptible patching task. The future must be run by a tokio
+    /// runtime.
+    ///
+    /// Returns the path of the new patcher if the patcher has updated itself
+    /// and must be restarted.
+    pub async fn run(self) -> Option<PathBuf> {
+        log::trace!("Patching thread started. Waiting for commands ...");
+        let Patcher {
+            config,
+            mut commands_rx,
+            events,
+        } = self;
+        let rx = &mut commands_rx;
+        let config = &config;
+        let events = &events;
+        let rate_limiter = DownloadRateLimiter::new(config.patching.download_speed_limit);
+        loop {
+            let cmd = rx.recv_async().await;
+            match cmd {
+                Err(e) => {
+                    log::error!("Failed to read from channel: {}", e);
+                    return None;
+                }
+                Ok(cmd) => match cmd {
+                    PatcherCommand::Quit => break,
+                    PatcherCommand::StartUpdate => {
+                        let new_exe_path = update_game(events, config, &rate_limiter, rx).await;
+                        if let Some(exe_path) = new_exe_path {
+                            events.send(PatcherEvent::RelaunchRequired {
+                                exe_path: exe_path.clone(),
+                            });
+                            return Some(exe_path);
+                        }
+                    }
+                    PatcherCommand::ApplyPatch(patch_file_path) => {
+                        apply_single_patch(patch_file_path, events, config);
+                    }
+                    PatcherCommand::SetDownloadSpeedLimit(bytes_per_sec) => {
+                        rate_limiter.set_limit(bytes_per_sec);
+                    }
+                    _ => {}
+                },
+            }
+        }
+        None
+    }
+}
+
+/// Starts the automatic update process (download + patching)
+///
+/// Returns the path of the new patcher if the patcher has updated itself and
+/// must be restarted.
+async fn update_game(
+    events: &EventSender,
+    config: &EngineConfiguration,
+    rate_limiter: &DownloadRateLimiter,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> Option<PathBuf> {
+    // Try taking the update lock
+    match take_update_lock(config)
+        .with_context(|| "Failed to take the update lock")
+        .error_code(ErrorCode::UpdateLocked)
+    {
+        Err(err) => {
+            log::error!("{}", err);
+            events.send(PatcherEvent::Error(err));
+            None
+        }
+        Ok(lock_file) => {
+            // Tell the consumer and other processes that we're currently working
+            events.send(PatcherEvent::PatchingStarted);
+            let _guard = scopeguard::guard((), |_| {
+                let _ = lock_file.unlock();
+                events.send(PatcherEvent::PatchingStopped);
+            });
+
+            let res =
+                interruptible_update_routine(events, config, rate_limiter, patcher_thread_rx).await;
+            match res {
+                Err(err) => {
+                    log::error!("{}", err);
+                    events.send(PatcherEvent::Error(err));
+                    None
+                }
+                Ok(UpdateOutcome::PatcherReplaced(exe_path)) => {
+                    log::info!("Patcher updated, restarting");
+                    Some(exe_path)
+                }
+                Ok(UpdateOutcome::GameUpdated) => {
+                    events.send(PatcherEvent::Ready);
+                    log::info!("Patching finished!");
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Applies a manual patch given by the user
+fn apply_single_patch(
+    patch_file_path: impl AsRef<Path>,
+    events: &EventSender,
+    config: &EngineConfiguration,
+) {
+    // Try taking the update lock
+    match take_update_lock(config)
+        .with_context(|| "Failed to take the update lock")
+        .error_code(ErrorCode::UpdateLocked)
+    {
+        Err(err) => {
+            log::error!("{}", err);
+            events.send(PatcherEvent::Error(err));
+        }
+        Ok(lock_file) => {
+            // Tell the consumer and other processes that we're currently working
+            events.send(PatcherEvent::PatchingStarted);
+            let _guard = scopeguard::guard((), |_| {
+                let _ = lock_file.unlock();
+                events.send(PatcherEvent::PatchingStopped);
+            });
+
+            let patch_file_name = patch_file_path
+                .as_ref()
+                .file_name()
+                .unwrap_or_default()
+                .to_str()
+                .unwrap_or_default()
+                .to_string();
+            log::info!("Applying patch '{}'", patch_file_name);
+            let res = apply_patch(patch_file_path, config, &config.paths.game_directory)
+                .error_code(ErrorCode::PatchingFailed);
+            match res {
+                Err(err) => {
+                    log::error!("{}", err);
+                    events.send(PatcherEvent::Error(err));
+                }
+                Ok(()) => {
+                    log::info!("Done");
+                    events.send(PatcherEvent::ManualPatchApplied {
+                        file_name: patch_file_name,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Returns the patches that have been applied so far, oldest first.
+pub fn get_patch_history(config: &EngineConfiguration) -> Result<Vec<AppliedPatch>> {
+    let cache_file_path = &config.paths.cache_file;
+    match block_on(read_cache_file(cache_file_path)) {
+        Ok(patcher_cache) => Ok(patcher_cache.applied_patches),
+        // No patch has been applied yet
+        Err(_) if !cache_file_path.exists() => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fetches the patch list and returns the patches that haven't been applied
+/// yet.
+pub async fn get_pending_patches(config: &EngineConfiguration) -> Result<ThorPatchList> {
+    let mut patcher_cache = read_cache_file(&config.paths.cache_file)
+        .await
+        .unwrap_or_default();
+    // Nothing can cancel this
+    let (_tx, mut rx) = flume::bounded(1);
+    let (manifest, _, _) = select_configured_patch_server(
+        config,
+        &patcher_cache.patch_servers,
+        &DownloadRateLimiter::new(None),
+        &mut rx,
+    )
+    .await
+    .map_err(|e| match e {
+        InterruptibleFnError::Err(msg) => anyhow!(msg),
+        InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
+    })?;
+    let mut patch_list = manifest.patch_list();
+    patcher_cache.migrate_last_patch_index(&patch_list);
+    patch_list.retain(|patch| !patcher_cache.is_applied(patch, manifest.sha256(patch)));
+    Ok(patch_list)
+}
+
+/// Resets the patcher cache (which is used to keep track of already applied
+/// patches).
+pub fn reset_cache(config: &EngineConfiguration) -> Result<()> {
+    match std::fs::remove_file(&config.paths.cache_file) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Takes an advisory lock that prevents multiple instances of the patcher to
+/// update the game at the same time
+fn take_update_lock(config: &EngineConfiguration) -> Result<std::fs::File> {
+    let lock_file = std::fs::File::create(&config.paths.update_lock_file)?;
+    lock_file.try_lock(FileLockMode::Exclusive)?;
+
+    Ok(lock_file)
+}
+
+/// Main routine of the patching task.
+///
+/// This routine is written in a way that makes it interuptible (or cancellable)
+/// with a relatively low latency.
+async fn interruptible_update_routine(
+    events: &EventSender,
+    config: &EngineConfiguration,
+    rate_limiter: &DownloadRateLimiter,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> std::result::Result<UpdateOutcome, PatcherError> {
+    log::info!("Start patching");
+
+    // Try to read cache
+    let cache_file_path = &config.paths.cache_file;
+    let mut patcher_cache = read_cache_file(cache_file_path).await.unwrap_or_default();
+
+    // Find the best patch server that we can connect to
+    let (manifest, mirrors, server_stats) = select_configured_patch_server(
+        config,
+        &patcher_cache.patch_servers,
+        rate_limiter,
+        patcher_thread_rx,
+    )
+    .await
+    .error_code(ErrorCode::NoPatchServer)?;
+    for warning in &manifest.warnings {
+        events.send(PatcherEvent::PatchListWarning(warning.clone()));
+    }
+    events.send(PatcherEvent::PatchServersInUse {
+        names: mirrors.mirrors_in_use(),
+    });
+
+    // Update the patcher itself before applying patches
+    let update_res = update_patcher(
+        events,
+        config,
+        manifest.patcher.as_ref(),
+        rate_limiter,
+        patcher_thread_rx,
+    )
+    .await;
+    match update_res {
+        Ok(Some(exe_path)) => return Ok(UpdateOutcome::PatcherReplaced(exe_path)),
+        Ok(None) => {}
+        Err(InterruptibleFnError::Err(msg)) => log::warn!("Failed to update the patcher: {}", msg),
+        Err(InterruptibleFnError::Interrupted) => {
+            return Err(PatcherError::new(
+                ErrorCode::Canceled,
+                "Patching was canceled",
+            ))
+        }
+    }
+
+    let mut patch_list = manifest.patch_list();
+    log::debug!("Successfully fetched patch list: {:?}", patch_list);
+    // Remember the measurements for next time and convert the cache written
+    // by older versions of the patcher
+    patcher_cache.migrate_last_patch_index(&patch_list);
+    patcher_cache.patch_servers = server_stats;
+    if let Err(e) = write_cache_file(cache_file_path, &patcher_cache).await {
+        log::warn!("Failed to write cache file: {}.", e);
+    }
+
+    // Remove the downloads that aren't needed anymore
+    let download_dir_path = match &config.patching.download_cache_directory {
+        Some(directory) => PathBuf::from(directory),
+        None => config.paths.download_directory.clone(),
+    };
+    let download_cache = DownloadCache::open(&download_dir_path).error_code(ErrorCode::Internal)?;
+    if let Err(e) = download_cache.clean_up(
+        &patch_list,
+        |patch| patcher_cache.is_applied(patch, manifest.sha256(patch)),
+        config.patching.download_cache_size_limit,
+    ) {
+        log::warn!("Failed to clean up the download cache: {:#}.", e);
+    }
+
+    // Ignore already applied patches
+    patch_list.retain(|patch| !patcher_cache.is_applied(patch, manifest.sha256(patch)));
+    // Patches that require a newer version of the patcher cannot be applied
+    for details in patch_list.iter().filter_map(|patch| manifest.get(patch)) {
+        if !details.is_supported() {
+            let min_version = details.min_patcher_version.as_deref().unwrap_or_default();
+            if details.is_mandatory() {
+                return Err(PatcherError::new(
+                    ErrorCode::PatcherOutdated,
+                    format!(
+                        "'{}' requires version {} of the patcher or newer",
+                        details.file_name, min_version
+                    ),
+                ));
+            }
+            log::warn!(
+                "Skipping '{}' which requires version {} of the patcher or newer",
+                details.file_name,
+                min_version
+            );
+        }
+    }
+    patch_list.retain(|patch| {
+        manifest
+            .get(patch)
+            .map(|d| d.is_supported())
+            .unwrap_or(true)
+    });
+
+    // Download patches and apply them as soon as they're available
+    log::info!("Downloading and applying patches ...");
+    let download_context = DownloadContext {
+        client: reqwest::Client::new(),
+        mirrors,
+        manifest,
+        download_cache: &download_cache,
+        ensure_integrity: config.patching.check_integrity,
+        rate_limiter,
+        events,
+    };
+    download_and_apply_patches(
+        &download_context,
+        patch_list,
+        config,
+        &cache_file_path,
+        patcher_thread_rx,
+    )
+    .await?;
+    log::info!("Patches have been applied");
+    // Remove the download directory if there's nothing left in it
+    download_cache.remove_if_empty();
+
+    Ok(UpdateOutcome::GameUpdated)
+}
+
+/// Replaces the patcher's executable if a newer release is available.
+///
+/// Releases are described by the patcher version endpoint or, if it's not
+/// configured or unavailable, by the patch manifest. Returns the path of the
+/// new executable if it has been replaced.
+///
+/// The path is resolved before replacing the executable, as the running
+/// executable's path follows it when it's moved out of the way on some
+/// platforms.
+async fn update_patcher(
+    events: &EventSender,
+    config: &EngineConfiguration,
+    manifest_release: Option<&PatcherRelease>,
+    rate_limiter: &DownloadRateLimiter,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<Option<PathBuf>> {
+    let client = reqwest::Client::new();
+    let release = match &config.web.patcher_update_url {
+        None => manifest_release.cloned(),
+        Some(release_url) => match fetch_patcher_release(&client, release_url).await {
+            Ok(release) => Some(release),
+            Err(e) => {
+                log::warn!("Failed to check for a new patcher: {:#}", e);
+                manifest_release.cloned()
+            }
+        },
+    };
+    let release = match release {
+        Some(release) if release.is_newer() => release,
+        _ => return Ok(None),
+    };
+
+    log::info!("Updating the patcher to version {}", release.version);
+    events.send(PatcherEvent::PatcherUpdateInProgress {
+        version: release.version.clone(),
+    });
+    let exe_path = env::current_exe().map_err(|e| {
+        InterruptibleFnError::Err(format!("Failed to resolve the patcher's path: {}", e))
+    })?;
+    let new_exe_path = tokio::select! {
+        cancel_res = wait_for_cancellation(patching_thread_rx, |cmd| {
+            handle_update_command(cmd, rate_limiter)
+        }) => return Err(cancel_res),
+        download_res = download_patcher_release(
+            &client,
+            &release,
+            &exe_path,
+            &config.patching.trusted_public_keys,
+            rate_limiter,
+        ) => {
+            download_res.map_err(|e| InterruptibleFnError::Err(format!("{:#}", e)))?
+        }
+    };
+    replace_exe(&exe_path, &new_exe_path)
+        .map_err(|e| InterruptibleFnError::Err(format!("{:#}", e)))?;
+    Ok(Some(exe_path))
+}
+
+/// Selects the patch server to use among the configured ones, with the
+/// configured settings.
+///
+/// See `select_patch_server`.
+async fn select_configured_patch_server(
+    config: &EngineConfiguration,
+    previous_stats: &[PatchServerStats],
+    rate_limiter: &DownloadRateLimiter,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<(PatchManifest, PatchMirrors, Vec<PatchServerStats>)> {
+    log::info!("Looking for an available patch server ...");
+    let probe_timeout = config
+        .web
+        .patch_server_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PATCH_SERVER_TIMEOUT);
+    let parsing_mode = match config.web.strict_patch_list.unwrap_or(false) {
+        true => PatchListParsingMode::Strict,
+        false => PatchListParsingMode::Lenient,
+    };
+    select_patch_server(
+        config.web.patch_servers.as_slice(),
+        &config.web.preferred_patch_server,
+        probe_timeout,
+        parsing_mode,
+        previous_stats,
+        rate_limiter,
+        patching_thread_rx,
+    )
+    .await
+}
+
+/// Probes all the servers of `server_list` at once and selects the one to
+/// download patches from.
+///
+/// `preferred_server_name` is selected if it's available, otherwise the
+/// fastest available server is selected. Returns the selected server's patch
+/// list, the mirrors to download patches from and the measurements made.
+/// Patches are downloaded from all the available servers that serve the same
+/// patch list as the selected one. Servers that didn't answer are used as
+/// fallbacks.
+async fn select_patch_server(
+    server_list: &[PatchServerInfo],
+    preferred_server_name: &Option<String>,
+    probe_timeout: Duration,
+    parsing_mode: PatchListParsingMode,
+    previous_stats: &[PatchServerStats],
+    rate_limiter: &DownloadRateLimiter,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<(PatchManifest, PatchMirrors, Vec<PatchServerStats>)> {
+    if let Some(preferred_server_name) = preferred_server_name {
+        if !server_list.iter().any(|s| &s.name == preferred_server_name) {
+            log::warn!(
+                "'{}' isn't in the list of patch servers",
+                preferred_server_name
+            );
+        }
+    }
+
+    // Probe all servers concurrently
+    let client = &reqwest::Client::new();
+    let probes = futures::future::join_all(server_list.iter().map(|server| async move {
+        let probe = probe_patch_server(client, server, parsing_mode);
+        let result = tokio::time::timeout(probe_timeout, probe)
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Timed out")));
+        (server, result)
+    }));
+    let probe_results = tokio::select! {
+        cancel_res = wait_for_cancellation(patching_thread_rx, |cmd| {
+            handle_update_command(cmd, rate_limiter)
+        }) => return Err(cancel_res),
+        probe_results = probes => probe_results,
+    };
+
+    let mut available_servers = vec![];
+    let mut unavailable_servers = vec![];
+    for (server, result) in probe_results {
+        match result {
+            Ok(probe) => {
+                log::info!(
+                    "'{}' is available (latency: {}ms, speed: {:?}B/s)",
+                    server.name,
+                    probe.2.latency_ms,
+                    probe.2.bytes_per_sec
+                );
+                available_servers.push((server, probe));
+            }
+            Err(e) => {
+                log::warn!("'{}' is unavailable: {:#}", server.name, e);
+                unavailable_servers.push(server);
+            }
+        }
+    }
+
+    // Fastest servers first, with the preferred server on top
+    let is_preferred =
+        |server: &PatchServerInfo| Some(&server.name) == preferred_server_name.as_ref();
+    available_servers.sort_by_key(|(server, (_, _, stats))| {
+        (!is_preferred(server), estimated_download_time(stats))
+    });
+    // Servers that failed to answer are only used as a last resort, ordered
+    // with the results of the previous probes
+    unavailable_servers.sort_by_key(|server| {
+        previous_stats
+            .iter()
+            .find(|stats| stats.name == server.name)
+            .map(estimated_download_time)
+            .unwrap_or(u64::MAX)
+    });
+
+    let server_stats: Vec<PatchServerStats> = available_servers
+        .iter()
+        .map(|(_, (_, _, stats))| stats.clone())
+        .collect();
+    let mut available_servers = available_servers.into_iter();
+    let (manifest, selected_mirror) = match available_servers.next() {
+        Some((server, (manifest, patch_url, _))) => (
+            manifest,
+            PatchMirror {
+                name: server.name.clone(),
+                patch_url,
+            },
+        ),
+        None => {
+            return Err(InterruptibleFnError::Err(
+                "None of the patch servers are available at the moment".to_string(),
+            ))
+        }
+    };
+    let mut mirrors = vec![selected_mirror];
+    let patch_list = manifest.patch_list();
+    for (server, (server_manifest, patch_url, _)) in available_servers {
+        if server_manifest.patch_list() == patch_list {
+            mirrors.push(PatchMirror {
+                name: server.name.clone(),
+                patch_url,
+            });
+        } else {
+            log::warn!(
+                "'{}' doesn't serve the same patches as '{}'",
+                server.name,
+                mirrors[0].name
+            );
+        }
+    }
+    let fallback_mirrors = unavailable_servers
+        .into_iter()
+        .filter_map(|server| {
+            Url::parse(server.patch_url.as_str())
+                .ok()
+                .map(|patch_url| PatchMirror {
+                    name: server.name.clone(),
+                    patch_url,
+                })
+        })
+        .collect();
+    let mirrors = PatchMirrors::new(mirrors, fallback_mirrors);
+
+    Ok((manifest, mirrors, server_stats))
+}
+
+/// Returns the estimated time (in milliseconds) a server would take to serve
+/// a typical patch.
+fn estimated_download_time(stats: &PatchServerStats) -> u64 {
+    // Size of a typical patch (1 MiB)
+    const REFERENCE_PATCH_SIZE: u64 = 1024 * 1024;
+    let transfer_time_ms = match stats.bytes_per_sec {
+        Some(bytes_per_sec) if bytes_per_sec > 0 => REFERENCE_PATCH_SIZE * 1000 / bytes_per_sec,
+        _ => 0,
+    };
+    stats.latency_ms.saturating_add(transfer_time_ms)
+}
+
+/// Checks whether a patch server is up or not and measures its latency and
+/// its throughput.
+/// Returns the manifest of the patches served by the server, the URL to
+/// download them from as well as the measurements.
+async fn probe_patch_server(
+    client: &reqwest::Client,
+    server_info: &PatchServerInfo,
+    parsing_mode: PatchListParsingMode,
+) -> Result<(PatchManifest, Url, PatchServerStats)> {
+    // Maximum number of bytes downloaded to measure a server's throughput
+    const SAMPLE_SIZE: u64 = 64 * 1024;
+    // Parse URLs
+    let patch_list_url = Url::parse(server_info.plist_url.as_str())
+        .with_context(|| "Failed to parse 'plist_url'")?;
+    let patch_url = Url::parse(server_info.patch_url.as_str())
+        .with_context(|| "Failed to parse 'patch_url'")?;
+
+    // Fetch the manifest if there's one, the plist otherwise
+    let mut start_time = Instant::now();
+    let mut manifest = None;
+    if let Some(manifest_url) = &server_info.manifest_url {
+        match fetch_patch_manifest(client, manifest_url, parsing_mode).await {
+            Ok(v) => manifest = Some(v),
+            Err(e) => {
+                log::warn!(
+                    "Failed to retrieve the manifest of '{}', using the patch list instead: {:#}",
+                    server_info.name,
+                    e
+                );
+                start_time = Instant::now();
+            }
+        }
+    }
+    let manifest = match manifest {
+        Some(v) => v,
+        None => {
+            let resp = client
+                .get(patch_list_url)
+                .send()
+                .await
+                .with_context(|| "Failed to GET URL")?;
+            let (patch_list, warnings) = parse_patch_list_response(resp, parsing_mode)
+                .await
+                .with_context(|| "Failed to retrieve the patch list")?;
+            PatchManifest {
+                warnings,
+                ..PatchManifest::from_patch_list(patch_list)
+            }
+        }
+    };
+    let latency = start_time.elapsed();
+
+    // Ensure that the server serves the patches (check the first patch of the
+    // list) and measure its throughput
+    let bytes_per_sec = if let Some(patch_details) = manifest.patches.first() {
+        let start_time = Instant::now();
+        let mut patch_resp = client
+            .get(patch_details.download_url(&patch_url)?)
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes=0-{}", SAMPLE_SIZE - 1),
+            )
+            .send()
+            .await
+            .with_context(|| "Failed to GET patch")?
+            .error_for_status()?;
+        let mut downloaded_bytes: u64 = 0;
+        while let Some(chunk) = patch_resp.chunk().await? {
+            downloaded_bytes += chunk.len() as u64;
+            // Servers that don't support range requests send the whole file
+            if downloaded_bytes >= SAMPLE_SIZE {
+                break;
+            }
+        }
+        let elapsed = start_time.elapsed().as_secs_f32();
+        if elapsed > 0.0 {
+            Some((downloaded_bytes as f32 / elapsed).round() as u64)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    Ok((
+        manifest,
+        patch_url,
+        PatchServerStats {
+            name: server_info.name.clone(),
+            latency_ms: latency.as_millis() as u64,
+            bytes_per_sec,
+        },
+    ))
+}
+
+/// Downloads and parses a patch manifest.
+async fn fetch_patch_manifest(
+    client: &reqwest::Client,
+    manifest_url: &str,
+    parsing_mode: PatchListParsingMode,
+) -> Result<PatchManifest> {
+    let manifest_url =
+        Url::parse(manifest_url).with_context(|| "Failed to parse 'manifest_url'")?;
+    let resp = client
+        .get(manifest_url)
+        .send()
+        .await
+        .with_context(|| "Failed to GET URL")?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("Manifest not found on the remote server"));
+    }
+    let manifest_content = resp.text().await.with_context(|| "Invalid response body")?;
+    log::info!("Parsing patch manifest...");
+
+    let (mut manifest, diagnostics) = PatchManifest::from_string(&manifest_content, parsing_mode)?;
+    manifest.warnings = diagnostics
+        .into_iter()
+        .map(|message| {
+            log::warn!("Invalid manifest entry ({})", message);
+            PatchListWarning {
+                line_number: None,
+                message,
+            }
+        })
+        .collect();
+    Ok(manifest)
+}
+
+/// Parses the response to a request for a 'plist.txt' file.
+///
+/// Returns a vector of `ThorPatchInfo` in case of success. In lenient mode,
+/// invalid lines are skipped and returned as warnings.
+async fn parse_patch_list_response(
+    resp: reqwest::Response,
+    parsing_mode: PatchListParsingMode,
+) -> Result<(ThorPatchList, Vec<PatchListWarning>)> {
+    if !resp.status().is_success() {
+        return Err(anyhow!("Patch list file not found on the remote server"));
+    }
+    let patch_index_content = resp.text().await.with_context(|| "Invalid responde body")?;
+    log::info!("Parsing patch index...");
+
+    let (patch_list, diagnostics) =
+        thor::parse_patch_list(patch_index_content.as_str(), parsing_mode)?;
+    let warnings = diagnostics
+        .into_iter()
+        .map(|diagnostic| {
+            log::warn!("Invalid patch list entry ({})", diagnostic);
+            PatchListWarning {
+                line_number: Some(diagnostic.line_number),
+                message: diagnostic.message,
+            }
+        })
+        .collect();
+    Ok((patch_list, warnings))
+}
+
+/// Downloads and applies a list of patches (described with a `ThorPatchList`).
+///
+/// Files are downloaded from all the usable mirrors at once.
+/// Failed downloads are retried and mirrors that keep failing stop being used
+/// for the remaining downloads. When patching in place, patches are applied in
+/// order as soon as they (and all the patches that precede them) have been
+/// downloaded, while the following patches keep being downloaded. Otherwise,
+/// they're applied once all of them have been downloaded, so that each GRF is
+/// rebuilt only once. Downloaded files are removed as soon as they've been
+/// applied.
+///
+/// This function is interruptible.
+async fn download_and_apply_patches(
+    download_context: &DownloadContext<'_>,
+    patch_list: ThorPatchList,
+    config: &EngineConfiguration,
+    cache_file_path: impl AsRef<Path>,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> std::result::Result<(), PatcherError> {
+    let max_parallel_downloads = config
+        .patching
+        .max_parallel_downloads
+        .unwrap_or(DEFAULT_MAX_PARALLEL_DOWNLOADS)
+        .max(1);
+    const ONE_SECOND: Duration = Duration::from_secs(1);
+    let current_working_dir = &config.paths.game_directory;
+    let events = download_context.events;
+    let rate_limiter = download_context.rate_limiter;
+    // Shared value that contains the number of downloaded patches
+    let shared_patch_number = AtomicUsize::new(0_usize);
+    // Shared tuple that's used to compute the download speed
+    let shared_progress_state = Arc::new(std::sync::Mutex::new((Instant::now(), 0_u64)));
+    // Shared state that's used to compute the number of bytes left to download
+    let download_tracker = DownloadTracker::new(
+        patch_list
+            .iter()
+            .map(|patch| {
+                download_context
+                    .manifest
+                    .get(patch)
+                    .and_then(|details| details.size)
+            })
+            .collect(),
+    );
+
+    let patch_count = patch_list.len();
+    events.send(PatcherEvent::DownloadInProgress(download_tracker.progress(
+        0,
+        patch_count,
+        0,
+    )));
+    // Stream of "PendingPatch" downloaded concurrently with an unordered_buffer
+    let shared_patch_number = &shared_patch_number;
+    let shared_progress_state = &shared_progress_state;
+    let download_tracker = &download_tracker;
+    let mut downloads = futures::stream::iter(patch_list.into_iter().enumerate().map(
+        |(position, patch_info)| async move {
+            // Setup a progress callback that'll report the current download speed
+            let shared_patch_number_ref = shared_patch_number;
+            let shared_state = shared_progress_state.clone();
+            let mut last_downloaded_bytes: Option<u64> = None;
+            let mut progress_callback = move |dl_now, dl_total| {
+                download_tracker.update(position, dl_now, Some(dl_total).filter(|&t| t > 0));
+                // Resumed downloads start from the bytes downloaded previously,
+                // which don't count in the download speed. Downloads may also
+                // start over from 0 when they're retried.
+                let dl_delta = dl_now - last_downloaded_bytes.unwrap_or(dl_now).min(dl_now);
+                // Return download speed if the required time has elapsed (1s)
+                let downloaded_bytes_per_sec = {
+                    if let Ok(mut shared_state) = shared_state.lock() {
+                        shared_state.1 += dl_delta;
+                        if shared_state.0.elapsed() >= ONE_SECOND {
+                            let downloaded_bytes_per_sec =
+                                (shared_state.1 as f32 / shared_state.0.elapsed().as_secs_f32())
+                                    .round() as u64;
+                            shared_state.0 = Instant::now();
+                            shared_state.1 = 0;
+                            Some(downloaded_bytes_per_sec)
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    }
+                };
+                // If speed is "available", report it
+                if let Some(downloaded_bytes_per_sec) = downloaded_bytes_per_sec {
+                    events.send(PatcherEvent::DownloadInProgress(download_tracker.progress(
+                        shared_patch_number_ref.load(Ordering::SeqCst),
+                        patch_count,
+                        downloaded_bytes_per_sec,
+                    )));
+                }
+                last_downloaded_bytes = Some(dl_now);
+            };
+
+            let download_res =
+                download_patch(download_context, &patch_info, &mut progress_callback).await;
+
+            // Update status
+            shared_patch_number_ref.fetch_add(1, Ordering::SeqCst);
+            download_tracker.finish(position);
+
+            // File's been downloaded, it can be queued. Optional patches that
+            // couldn't be downloaded are skipped until the next update.
+            let is_mandatory = download_context
+                .manifest
+                .get(&patch_info)
+                .map(|details| details.is_mandatory())
+                .unwrap_or(true);
+            let pending_patch = match download_res {
+                Ok((local_file_path, server)) => {
+                    events.send(PatcherEvent::PatchDownloaded {
+                        file_name: patch_info.file_name.clone(),
+                    });
+                    Some(PendingPatch {
+                        info: patch_info,
+                        local_file_path,
+                        server,
+                    })
+                }
+                Err(e) if !is_mandatory => {
+                    log::warn!(
+                        "Skipping optional patch '{}': {:#}",
+                        patch_info.file_name,
+                        e
+                    );
+                    None
+                }
+                Err(e) => return Err(e),
+            };
+            Ok((position, pending_patch)) as Result<(usize, Option<PendingPatch>)>
+        },
+    ))
+    .buffer_unordered(max_parallel_downloads);
+
+    // Downloaded patches that cannot be applied yet, indexed by position
+    let mut downloaded_patches: BTreeMap<usize, Option<PendingPatch>> = BTreeMap::new();
+    let mut next_position: usize = 0;
+    let mut applied_patch_count: usize = 0;
+    let mut downloads_finished = false;
+    let mut running_batch: Option<tokio::task::JoinHandle<PatchBatchResult>> = None;
+    // Rebuilding a GRF out of place is expensive, so in that case patches are
+    // only applied once they've all been downloaded
+    let apply_when_ready = config.patching.in_place;
+    loop {
+        // Start applying the patches that are ready if we're not already busy
+        if running_batch.is_none() && (apply_when_ready || downloads_finished) {
+            let mut patch_batch = vec![];
+            while let Some(pending_patch) = downloaded_patches.remove(&next_position) {
+                patch_batch.extend(pending_patch);
+                next_position += 1;
+            }
+            if !patch_batch.is_empty() {
+                let config = config.clone();
+                let current_working_dir = current_working_dir.clone();
+                running_batch = Some(tokio::task::spawn_blocking(move || {
+                    apply_patch_batch(patch_batch, &config, current_working_dir)
+                }));
+            } else if downloads_finished {
+                break;
+            }
+        }
+
+        tokio::select! {
+            cancel_res = wait_for_cancellation(patching_thread_rx, |cmd| {
+                handle_update_command(cmd, rate_limiter)
+            }) => {
+                // Let the patches that are being applied be applied
+                if let Some(running_batch) = running_batch {
+                    let _ = on_patch_batch_applied(running_batch.await, &cache_file_path, events).await;
+                }
+                return Err(cancel_res).error_code(ErrorCode::Internal);
+            }
+            download_res = downloads.next(), if !downloads_finished => match download_res {
+                None => {
+                    log::info!("Patches have been downloaded");
+                    downloads_finished = true;
+                }
+                Some(Ok((position, pending_patch))) => {
+                    downloaded_patches.insert(position, pending_patch);
+                }
+                Some(Err(e)) => {
+                    if let Some(running_batch) = running_batch {
+                        let _ = on_patch_batch_applied(running_batch.await, &cache_file_path, events).await;
+                    }
+                    return Err(PatcherError::new(
+                        ErrorCode::DownloadFailed,
+                        format!("Failed to download patches: {:#}", e),
+                    ));
+                }
+            },
+            batch_res = async { running_batch.as_mut().unwrap().await }, if running_batch.is_some() => {
+                running_batch = None;
+                applied_patch_count += on_patch_batch_applied(batch_res, &cache_file_path, events)
+                    .await
+                    .error_code(ErrorCode::PatchingFailed)?;
+                events.send(PatcherEvent::InstallationInProgress {
+                    installed_patches: applied_patch_count,
+                    total_patches: patch_count,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keeps track of the bytes downloaded for each patch of an update, in order
+/// to estimate the time left.
+struct DownloadTracker {
+    patches: std::sync::Mutex<Vec<(u64, Option<u64>)>>, // Downloaded bytes, Bytes to download (if known)
+}
+
+impl DownloadTracker {
+    /// `sizes` contains the size of each patch, if known.
+    fn new(sizes: Vec<Option<u64>>) -> Self {
+        Self {
+            patches: std::sync::Mutex::new(sizes.into_iter().map(|size| (0, size)).collect()),
+        }
+    }
+
+    /// Updates the progress of the download of the patch at `position`.
+    fn update(&self, position: usize, downloaded_bytes: u64, total_bytes: Option<u64>) {
+        if let Ok(mut patches) = self.patches.lock() {
+            let patch = &mut patches[position];
+            patch.0 = downloaded_bytes;
+            if total_bytes.is_some() {
+                patch.1 = total_bytes;
+            }
+        }
+    }
+
+    /// Indicates that there's nothing left to download for the patch at
+    /// `position` (it's been downloaded, reused or skipped).
+    fn finish(&self, position: usize) {
+        if let Ok(mut patches) = self.patches.lock() {
+            let patch = &mut patches[position];
+            patch.1 = Some(patch.0);
+        }
+    }
+
+    /// Returns the progress of the downloads. The time left is only estimated
+    /// once the size of all the patches is known.
+    fn progress(
+        &self,
+        downloaded_patches: usize,
+        total_patches: usize,
+        bytes_per_sec: u64,
+    ) -> DownloadProgress {
+        let (downloaded_bytes, total_bytes) = match self.patches.lock() {
+            Ok(patches) => (
+                patches.iter().map(|patch| patch.0).sum(),
+                patches.iter().map(|patch| patch.1).sum::<Option<u64>>(),
+            ),
+            Err(_) => (0, None),
+        };
+        let eta_secs = match total_bytes {
+            Some(total_bytes) if bytes_per_sec > 0 => {
+                Some(total_bytes.saturating_sub(downloaded_bytes) / bytes_per_sec)
+            }
+            _ => None,
+        };
+        DownloadProgress {
+            downloaded_patches,
+            total_patches,
+            bytes_per_sec,
+            downloaded_bytes,
+            total_bytes,
+            eta_secs,
+        }
+    }
+}
+
+/// Patches that were successfully applied in a batch as well as the error that
+/// interrupted the batch, if any
+type PatchBatchResult = (Vec<AppliedPatch>, Result<()>);
+
+/// Updates the cache file once a batch of patches has been applied.
+///
+/// Returns the number of patches that have been applied.
+async fn on_patch_batch_applied(
+    batch_res: std::result::Result<PatchBatchResult, tokio::task::JoinError>,
+    cache_file_path: impl AsRef<Path>,
+    events: &EventSender,
+) -> InterruptibleFnResult<usize> {
+    let (applied_patches, result) = batch_res
+        .map_err(|e| InterruptibleFnError::Err(format!("Failed to apply patches: {}.", e)))?;
+    for applied_patch in &applied_patches {
+        events.send(PatcherEvent::PatchApplied {
+            file_name: applied_patch.file_name.clone(),
+        });
+    }
+    // Record the successfully applied patches in the ledger
+    let applied_patch_count = applied_patches.len();
+    if applied_patch_count > 0 {
+        if let Err(e) = update_cache_file(&cache_file_path, |cache| {
+            cache.record_applied(applied_patches);
+        })
+        .await
+        {
+            log::warn!("Failed to write cache file: {}.", e);
+        }
+    }
+    result.map_err(|e| InterruptibleFnError::Err(format!("Failed to apply patches: {:#}", e)))?;
+    Ok(applied_patch_count)
+}
+
+/// Handles the commands received while an update is in progress.
+fn handle_update_command(cmd: PatcherCommand, rate_limiter: &DownloadRateLimiter) {
+    match cmd {
+        PatcherCommand::SetDownloadSpeedLimit(bytes_per_sec) => {
+            rate_limiter.set_limit(bytes_per_sec);
+        }
+        _ => log::warn!("Ignoring command received while updating"),
+    }
+}
+
+/// Downloads a single patch described with a `ThorPatchInfo` and checks its
+/// integrity if required.
+///
+/// Transient errors are retried with an exponential backoff. If the mirror
+/// used keeps failing, another one is used. Mirrors that don't serve the patch
+/// (e.g., 404 or 429 responses) are skipped for this download only, the
+/// download fails if none of them serves it. Patches with an absolute URL in
+/// the manifest are downloaded from that URL only.
+///
+/// Returns the path of the downloaded file and the name of the patch server it
+/// was downloaded from (None if a previous download was reused).
+async fn download_patch<CB: FnMut(u64, u64)>(
+    download_context: &DownloadContext<'_>,
+    patch_info: &ThorPatchInfo,
+    mut progress_callback: CB,
+) -> Result<(PathBuf, Option<String>)> {
+    let patch_details = download_context.manifest.get(patch_info);
+    // Reuse previous downloads if possible
+    if let Some(local_file_path) = download_context.download_cache.get(patch_info).await {
+        if is_download_genuine(&local_file_path, patch_details).await? {
+            log::info!("Using cached download of '{}'", patch_info.file_name);
+            return Ok((local_file_path, None));
+        }
+        log::warn!(
+            "Cached download of '{}' doesn't match the manifest",
+            patch_info.file_name
+        );
+    }
+
+    // Patches hosted outside of the patch servers
+    if let Some(url) = patch_details.and_then(|d| d.url.as_ref()) {
+        let patch_file_url = Url::parse(url).with_context(|| "Invalid patch URL")?;
+        let source_name = patch_file_url.host_str().unwrap_or_default().to_string();
+        let local_file_path = download_patch_with_retries(
+            download_context,
+            &patch_file_url,
+            &source_name,
+            patch_info,
+            &mut progress_callback,
+        )
+        .await?;
+        return Ok((local_file_path, Some(source_name)));
+    }
+
+    let mirrors = &download_context.mirrors;
+    let mut last_error = None;
+    let mut excluded_mirrors = vec![];
+    while let Some(mirror_lease) = mirrors.acquire(&excluded_mirrors) {
+        let mirror = mirror_lease.mirror();
+        let patch_file_url = mirror
+            .patch_url
+            .join(patch_info.file_name.as_str())
+            .with_context(|| {
+                format!(
+                    "Invalid file name '{}' given in patch list file",
+                    patch_info.file_name
+                )
+            })?;
+        let result = download_patch_with_retries(
+            download_context,
+            &patch_file_url,
+            &mirror.name,
+            patch_info,
+            &mut progress_callback,
+        )
+        .await;
+        match result {
+            Ok(local_file_path) => return Ok((local_file_path, Some(mirror.name.clone()))),
+            Err(e) if is_mirror_error(&e) => {
+                // The mirror might still serve the other patches
+                log::warn!(
+                    "Failed to download '{}' from '{}': {:#}",
+                    patch_info.file_name,
+                    mirror.name,
+                    e
+                );
+                excluded_mirrors.push(mirror_lease.index());
+                last_error = Some(e);
+                continue;
+            }
+            Err(e) if !is_transient_error(&e) => return Err(e),
+            Err(e) => last_error = Some(e),
+        }
+
+        // Stop using this mirror, if another download hasn't already
+        if mirrors.report_failure(mirror_lease.index()) {
+            log::warn!("'{}' keeps failing, not using it anymore", mirror.name);
+            download_context
+                .events
+                .send(PatcherEvent::PatchServersInUse {
+                    names: mirrors.mirrors_in_use(),
+                });
+        }
+    }
+
+    let last_error = last_error.unwrap_or_else(|| anyhow!("No patch server to download from"));
+    Err(last_error.context("None of the patch servers are available at the moment"))
+}
+
+/// Downloads a single patch from `patch_file_url`, retrying transient errors
+/// with an exponential backoff.
+///
+/// Returns the last error once all the attempts have failed.
+async fn download_patch_with_retries<CB: FnMut(u64, u64)>(
+    download_context: &DownloadContext<'_>,
+    patch_file_url: &Url,
+    source_name: &str,
+    patch_info: &ThorPatchInfo,
+    mut progress_callback: CB,
+) -> Result<PathBuf> {
+    const MAX_ATTEMPTS: usize = 3;
+    const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+    let mut retry_delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let result = download_patch_from_url(
+            download_context,
+            patch_file_url,
+            patch_info,
+            &mut progress_callback,
+        )
+        .await;
+        match result {
+            Ok(local_file_path) => {
+                // Keep track of the download in case it cannot be applied
+                // during this update
+                if let Err(e) = download_context.download_cache.insert(patch_info).await {
+                    log::warn!("Failed to cache '{}': {:#}.", patch_info.file_name, e);
+                }
+                return Ok(local_file_path);
+            }
+            Err(e) if !is_transient_error(&e) || attempt == MAX_ATTEMPTS => return Err(e),
+            Err(e) => {
+                log::warn!(
+                    "Attempt {}/{} to download '{}' from '{}' failed: {:#}",
+                    attempt,
+                    MAX_ATTEMPTS,
+                    patch_info.file_name,
+                    source_name,
+                    e
+                );
+            }
+        }
+        tokio::time::sleep(retry_delay).await;
+        retry_delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Downloads a single patch from `patch_file_url` and checks its integrity if
+/// required.
+///
+/// Corrupt archives (and archives that don't match the manifest) are removed
+/// and reported as transient errors.
+async fn download_patch_from_url<CB: FnMut(u64, u64)>(
+    download_context: &DownloadContext<'_>,
+    patch_file_url: &Url,
+    patch_info: &ThorPatchInfo,
+    progress_callback: CB,
+) -> Result<PathBuf> {
+    let local_file_path = download_patch_to_directory(
+        &download_context.client,
+        patch_file_url,
+        patch_info,
+        download_context.download_cache.directory(),
+        download_context.rate_limiter,
+        progress_callback,
+    )
+    .await?;
+
+    // Check the archive against the manifest
+    let patch_details = download_context.manifest.get(patch_info);
+    if !is_download_genuine(&local_file_path, patch_details).await? {
+        let _ = std::fs::remove_file(&local_file_path);
+        return Err(TransientError(format!(
+            "Archive '{}' doesn't match the manifest",
+            patch_info.file_name
+        ))
+        .into());
+    }
+
+    // Check the archive's integrity if required
+    let context = || {
+        format!(
+            "Failed to check archive's integrity: '{}'",
+            patch_info.file_name
+        )
+    };
+    if download_context.ensure_integrity
+        && !is_archive_valid(&local_file_path).with_context(context)?
+    {
+        let _ = std::fs::remove_file(&local_file_path);
+        return Err(
+            TransientError(format!("Archive '{}' is corrupt", patch_info.file_name)).into(),
+        );
+    }
+
+    Ok(local_file_path)
+}
+
+/// Checks a downloaded patch against the size and the SHA-256 hash given in
+/// the manifest, if any.
+async fn is_download_genuine(
+    local_file_path: &Path,
+    patch_details: Option<&PatchManifestEntry>,
+) -> Result<bool> {
+    let (expected_size, expected_sha256) = match patch_details {
+        Some(details) if details.size.is_some() || details.sha256.is_some() => {
+            (details.size, details.sha256.clone())
+        }
+        _ => return Ok(true),
+    };
+    let local_file_path = local_file_path.to_path_buf();
+    let (size, sha256) = tokio::task::spawn_blocking(move || measure_file(local_file_path))
+        .await
+        .context("Failed to hash downloaded file")??;
+    let is_size_valid = expected_size.map(|v| v == size).unwrap_or(true);
+    let is_hash_valid = expected_sha256
+        .map(|v| v.eq_ignore_ascii_case(&sha256))
+        .unwrap_or(true);
+    Ok(is_size_valid && is_hash_valid)
+}
+
+fn is_archive_valid(archive_path: impl AsRef<Path>) -> Result<bool> {
+    let mut archive =
+        ThorArchive::open(archive_path.as_ref()).with_context(|| "Failed to open archive")?;
+    match archive.is_valid() {
+        Err(e) => {
+            if let GrufError::EntryNotFound = e {
+                // No integrity file present, consider the archive valid
+                Ok(true)
+            } else {
+                // Only consider this an error if the integrity file was found
+                Err(anyhow!(
+                    "Archive's integrity file is invalid: {}",
+                    e.to_string(),
+                ))
+            }
+        }
+        Ok(v) => Ok(v),
+    }
+}
+
+/// Applies a batch of downloaded patches to GRFs and/or to the game client's
+/// files and removes them once they've been applied.
+///
+/// Patches that target the same GRF are applied all at once (so that each GRF
+/// is rebuilt only once), in the patch list's order. Patches for the game
+/// client's directory may overwrite GRFs though, so patches for a GRF are
+/// never merged across them. Patches of different batches are never merged
+/// either, a batch only contains the patches that had been downloaded when it
+/// was started.
+fn apply_patch_batch(
+    patch_batch: Vec<PendingPatch>,
+    config: &EngineConfiguration,
+    current_working_dir: impl AsRef<Path>,
+) -> PatchBatchResult {
+    let mut applied_patches = vec![];
+    let mut patch_groups: Vec<Vec<OpenedPatch>> = vec![];
+    let mut open_res = Ok(());
+    for pending_patch in patch_batch {
+        let opened_patch = match open_pending_patch(pending_patch, config, &current_working_dir) {
+            Ok(v) => v,
+            Err(e) => {
+                // Apply the patches that precede the one that can't be opened
+                open_res = Err(e);
+                break;
+            }
+        };
+        let patch_group = match &opened_patch.target {
+            PatchTarget::Grf(_) => patch_groups
+                .iter_mut()
+                .rev()
+                .take_while(|group| matches!(group[0].target, PatchTarget::Grf(_)))
+                .find(|group| group[0].target == opened_patch.target),
+            PatchTarget::Directory(_) => patch_groups
+                .last_mut()
+                .filter(|group| group[0].target == opened_patch.target),
+        };
+        match patch_group {
+            Some(patch_group) => patch_group.push(opened_patch),
+            None => patch_groups.push(vec![opened_patch]),
+        }
+    }
+
+    for patch_group in patch_groups {
+        let patch_names = patch_group
+            .iter()
+            .map(|p| p.info.file_name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        log::info!("Processing {}", patch_names);
+        let target = patch_group[0].target.clone();
+        let (patch_infos, mut thor_archives): (Vec<_>, Vec<_>) = patch_group
+            .into_iter()
+            .map(|p| ((p.info, p.local_file_path, p.server), p.archive))
+            .unzip();
+        if let Err(e) = apply_patches_to_target(&target, &mut thor_archives, config) {
+            return (
+                applied_patches,
+                Err(e.context(format!("Failed to apply '{}'", patch_names))),
+            );
+        }
+        // Downloaded files aren't needed anymore
+        drop(thor_archives);
+        let applied_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        for (patch_info, local_file_path, server) in patch_infos {
+            let sha256 = match measure_file(&local_file_path) {
+                Ok((_, sha256)) => Some(sha256),
+                Err(e) => {
+                    log::warn!("Failed to hash '{}': {:#}.", patch_info.file_name, e);
+                    None
+                }
+            };
+            if let Err(e) = std::fs::remove_file(&local_file_path) {
+                log::warn!("Failed to remove '{}': {}.", patch_info.file_name, e);
+            }
+            applied_patches.push(AppliedPatch {
+                index: patch_info.index,
+                file_name: patch_info.file_name,
+                sha256,
+                applied_at,
+                server,
+            });
+        }
+    }
+    (applied_patches, open_res)
+}
+
+/// Opens a downloaded patch and resolves its target.
+fn open_pending_patch(
+    pending_patch: PendingPatch,
+    config: &EngineConfiguration,
+    current_working_dir: impl AsRef<Path>,
+) -> Result<OpenedPatch> {
+    let archive = open_patch(&pending_patch.local_file_path, config)
+        .with_context(|| format!("Failed to open patch '{}'", pending_patch.info.file_name))?;
+    let target = PatchTarget::of(&archive, config, current_working_dir);
+    Ok(OpenedPatch {
+        info: pending_patch.info,
+        local_file_path: pending_patch.local_file_path,
+        server: pending_patch.server,
+        archive,
+        target,
+    })
+}
+
+/// Checks that an archive has been signed with one of the given public keys.
+fn is_archive_signed<R: Read + Seek>(
+    archive: &mut ThorArchive<R>,
+    trusted_public_keys: &[String],
+) -> Result<bool> {
+    let public_keys = trusted_public_keys
+        .iter()
+        .map(hex::decode)
+        .collect::<std::result::Result<Vec<Vec<u8>>, _>>()
+        .with_context(|| "Invalid public key in 'trusted_public_keys'")?;
+    match archive.is_signed_by(public_keys) {
+        Err(e) => {
+            if let GrufError::EntryNotFound = e {
+                Err(anyhow!("Archive is not signed"))
+            } else {
+                Err(anyhow!("Archive's signature is invalid: {}", e.to_string()))
+            }
+        }
+        Ok(v) => Ok(v),
+    }
+}
+
+/// Opens a THOR archive and makes sure it's been signed by a trusted key, if
+/// required.
+fn open_patch(
+    thor_archive_path: impl AsRef<Path>,
+    config: &EngineConfiguration,
+) -> Result<ThorArchive<std::fs::File>> {
+    let mut thor_archive = ThorArchive::open(thor_archive_path.as_ref())?;
+    // Refuse archives that haven't been signed by a trusted key, if required
+    if !config.patching.trusted_public_keys.is_empty()
+        && !is_archive_signed(&mut thor_archive, &config.patching.trusted_public_keys)?
+    {
+        return Err(anyhow!("Archive has not been signed by a trusted key"));
+    }
+    Ok(thor_archive)
+}
+
+fn apply_patch(
+    thor_archive_path: impl AsRef<Path>,
+    config: &EngineConfiguration,
+    current_working_dir: impl AsRef<Path>,
+) -> Result<()> {
+    let mut thor_archive = open_patch(thor_archive_path, config)?;
+    let target = PatchTarget::of(&thor_archive, config, current_working_dir);
+    apply_patches_to_target(&target, std::slice::from_mut(&mut thor_archive), config)
+}
+
+/// Applies a list of patches that share the same target all at once.
+fn apply_patches_to_target<R: Read + Seek>(
+    target: &PatchTarget,
+    thor_archives: &mut [ThorArchive<R>],
+    config: &EngineConfiguration,
+) -> Result<()> {
+    match target {
+        PatchTarget::Grf(target_grf_path) => {
+            // Patch GRF file
+            log::trace!("Target GRF: {:?}", target_grf_path);
+            let grf_patching_method = match config.patching.in_place {
+                true => GrfPatchingMethod::InPlace,
+                false => GrfPatchingMethod::OutOfPlace,
+            };
+            apply_patches_to_grf(
+                grf_patching_method,
+                config.patching.create_grf,
+                target_grf_path,
+                thor_archives,
+            )
+        }
+        // Patch root directory
+        PatchTarget::Directory(root_directory) => {
+            apply_patches_to_disk(root_directory, thor_archives)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClientConfiguration, EnginePaths};
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+
+    fn run_patch_server() -> Server {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/plist.txt"))
+                .respond_with(status_code(200).body("1 patch1.thor\n2 patch2.thor\n")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/data/patch1.thor"))
+                .respond_with(status_code(200).body(vec![0_u8; 1024])),
+        );
+        server
+    }
+
+    fn patch_server_info(name: &str, url: impl Fn(&str) -> String) -> PatchServerInfo {
+        PatchServerInfo {
+            name: name.to_string(),
+            plist_url: url("/plist.txt"),
+            patch_url: url("/data/"),
+            manifest_url: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_patch_server() {
+        let first_server = run_patch_server();
+        let second_server = run_patch_server();
+        let server_list = vec![
+            patch_server_info("unavailable", |path| format!("http://127.0.0.1:1{}", path)),
+            patch_server_info("first", |path| first_server.url(path).to_string()),
+            patch_server_info("second", |path| second_server.url(path).to_string()),
+        ];
+        let (_tx, mut rx) = flume::unbounded();
+
+        let (manifest, mirrors, server_stats) = select_patch_server(
+            &server_list,
+            &Some("second".to_string()),
+            Duration::from_secs(5),
+            PatchListParsingMode::Strict,
+            &[],
+            &DownloadRateLimiter::new(None),
+            &mut rx,
+        )
+        .await
+        .map_err(|_| ())
+        .unwrap();
+
+        assert_eq!(2, manifest.patches.len());
+        // The preferred server comes first and unavailable servers are fallbacks
+        assert_eq!(vec!["second", "first"], mirrors.mirrors_in_use());
+        assert!(mirrors.report_failure(0));
+        assert!(mirrors.report_failure(1));
+        assert_eq!(vec!["unavailable"], mirrors.mirrors_in_use());
+        assert_eq!(2, server_stats.len());
+        assert!(server_stats
+            .iter()
+            .all(|stats| stats.bytes_per_sec.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_probe_patch_server_manifest() {
+        let server = run_patch_server();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/manifest.yml")).respond_with(
+                status_code(200).body(
+                    "patches:
+  - index: 1
+    file_name: patch1.thor
+    size: 1024
+    description: First patch
+",
+                ),
+            ),
+        );
+        let client = reqwest::Client::new();
+        let mut server_info = patch_server_info("server", |path| server.url(path).to_string());
+
+        // The manifest is used if it's available
+        server_info.manifest_url = Some(server.url("/manifest.yml").to_string());
+        let (manifest, _, _) =
+            probe_patch_server(&client, &server_info, PatchListParsingMode::Strict)
+                .await
+                .unwrap();
+        assert_eq!(1, manifest.patches.len());
+        assert_eq!(Some(1024), manifest.patches[0].size);
+        // The patch list is used otherwise
+        server_info.manifest_url = Some(server.url("/missing.yml").to_string());
+        let (manifest, _, _) =
+            probe_patch_server(&client, &server_info, PatchListParsingMode::Strict)
+                .await
+                .unwrap();
+        assert_eq!(2, manifest.patches.len());
+        assert_eq!(None, manifest.patches[0].size);
+        assert!(manifest.warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_probe_patch_server_warnings() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/plist.txt"))
+                .times(2)
+                .respond_with(status_code(200).body("1 patch1.thor\nabc patch2.thor\n")),
+        );
+        let client = reqwest::Client::new();
+        let server_info = patch_server_info("server", |path| server.url(path).to_string());
+
+        assert!(
+            probe_patch_server(&client, &server_info, PatchListParsingMode::Strict)
+                .await
+                .is_err()
+        );
+        // Invalid entries are reported along with the manifest in lenient mode
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/data/patch1.thor"))
+                .respond_with(status_code(200).body(vec![0_u8; 16])),
+        );
+        let (manifest, _, _) =
+            probe_patch_server(&client, &server_info, PatchListParsingMode::Lenient)
+                .await
+                .unwrap();
+        assert_eq!(1, manifest.patches.len());
+        assert_eq!(1, manifest.warnings.len());
+        assert_eq!(Some(2), manifest.warnings[0].line_number);
+    }
+
+    #[tokio::test]
+    async fn test_download_and_apply_patches_out_of_place() {
+        use gruf::grf::GrfArchive;
+        use gruf::thor::ThorArchiveBuilder;
+
+        let game_directory = tempfile::tempdir().unwrap();
+        let download_directory = tempfile::tempdir().unwrap();
+        let server = Server::run();
+        // Patches for the same GRF finish downloading at different times
+        let patch_list = || -> ThorPatchList {
+            (1..=3)
+                .map(|index| ThorPatchInfo {
+                    index,
+                    file_name: format!("patch{}.thor", index),
+                })
+                .collect()
+        };
+        for (patch, delay_ms) in patch_list().iter().zip(&[0, 200, 400]) {
+            let mut thor_content = std::io::Cursor::new(vec![]);
+            {
+                let mut builder =
+                    ThorArchiveBuilder::new(&mut thor_content, true, None, false).unwrap();
+                builder
+                    .append_file_update(format!("data\\{}.txt", patch.index), b"1".as_ref())
+                    .unwrap();
+            }
+            server.expect(
+                Expectation::matching(request::method_path(
+                    "GET",
+                    format!("/data/{}", patch.file_name),
+                ))
+                .respond_with(delay_and_then(
+                    Duration::from_millis(*delay_ms),
+                    status_code(200).body(thor_content.into_inner()),
+                )),
+            );
+        }
+        let config = EngineConfiguration {
+            web: serde_yaml::from_str("patch_servers: []").unwrap(),
+            client: ClientConfiguration {
+                default_grf_name: "data.grf".to_string(),
+            },
+            patching: serde_yaml::from_str(
+                "in_place: false
+check_integrity: false
+create_grf: true
+",
+            )
+            .unwrap(),
+            paths: EnginePaths::new(game_directory.path(), "rpatchur"),
+        };
+        let (_, (events, event_stream)) = channels();
+        let (_tx, mut rx) = flume::unbounded();
+        let download_cache = DownloadCache::open(download_directory.path()).unwrap();
+        let rate_limiter = DownloadRateLimiter::new(None);
+        let download_context = DownloadContext {
+            client: reqwest::Client::new(),
+            mirrors: PatchMirrors::new(
+                vec![PatchMirror {
+                    name: "server".to_string(),
+                    patch_url: Url::parse(&server.url("/data/")).unwrap(),
+                }],
+                vec![],
+            ),
+            manifest: PatchManifest::from_patch_list(patch_list()),
+            download_cache: &download_cache,
+            ensure_integrity: false,
+            rate_limiter: &rate_limiter,
+            events: &events,
+        };
+
+        download_and_apply_patches(
+            &download_context,
+            patch_list(),
+            &config,
+            &config.paths.cache_file,
+            &mut rx,
+        )
+        .await
+        .unwrap();
+        drop(download_context);
+        drop(events);
+
+        // The GRF has been rebuilt once, with all the patches
+        let installation_events: Vec<PatcherEvent> = event_stream
+            .filter(|event| {
+                futures::future::ready(matches!(event, PatcherEvent::InstallationInProgress { .. }))
+            })
+            .collect()
+            .await;
+        assert_eq!(
+            vec![PatcherEvent::InstallationInProgress {
+                installed_patches: 3,
+                total_patches: 3,
+            }],
+            installation_events
+        );
+        let grf_archive = GrfArchive::open(game_directory.path().join("data.grf")).unwrap();
+        assert_eq!(3, grf_archive.file_count());
+    }
+
+    #[tokio::test]
+    async fn test_download_patch_mirror_errors() {
+        let download_directory = tempfile::tempdir().unwrap();
+        let servers: Vec<Server> = [404, 429, 200]
+            .iter()
+            .map(|&status| {
+                let server = Server::run();
+                server.expect(
+                    Expectation::matching(request::method_path("GET", "/data/patch1.thor"))
+                        .respond_with(status_code(status).body("patch")),
+                );
+                server.expect(
+                    Expectation::matching(request::method_path("GET", "/data/patch2.thor"))
+                        .respond_with(status_code(404)),
+                );
+                server
+            })
+            .collect();
+        let (_, (events, _event_stream)) = channels();
+        let download_cache = DownloadCache::open(download_directory.path()).unwrap();
+        let rate_limiter = DownloadRateLimiter::new(None);
+        let download_context = DownloadContext {
+            client: reqwest::Client::new(),
+            mirrors: PatchMirrors::new(
+                servers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, server)| PatchMirror {
+                        name: format!("server{}", i),
+                        patch_url: Url::parse(&server.url("/data/")).unwrap(),
+                    })
+                    .collect(),
+                vec![],
+            ),
+            manifest: PatchManifest::default(),
+            download_cache: &download_cache,
+            ensure_integrity: false,
+            rate_limiter: &rate_limiter,
+            events: &events,
+        };
+        let patch_info = |index| ThorPatchInfo {
+            index,
+            file_name: format!("patch{}.thor", index),
+        };
+
+        // Mirrors that don't serve the patch are skipped
+        let (_, server) = download_patch(&download_context, &patch_info(1), |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(Some("server2".to_string()), server);
+        // The download fails once every mirror has been tried
+        assert!(download_patch(&download_context, &patch_info(2), |_, _| {})
+            .await
+            .is_err());
+        // Mirrors are still used for the other patches
+        assert_eq!(
+            vec!["server0", "server1", "server2"],
+            download_context.mirrors.mirrors_in_use()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patcher_events() {
+        let game_directory = tempfile::tempdir().unwrap();
+        let config = EngineConfiguration {
+            web: serde_yaml::from_str("patch_servers: []").unwrap(),
+            client: ClientConfiguration {
+                default_grf_name: "data.grf".to_string(),
+            },
+            patching: serde_yaml::from_str(
+                "in_place: true
+check_integrity: true
+create_grf: false
+",
+            )
+            .unwrap(),
+            paths: EnginePaths::new(game_directory.path(), "rpatchur"),
+        };
+        let (patcher, handle, event_stream) = Patcher::new(config);
+        assert!(handle.send(PatcherCommand::SetDownloadSpeedLimit(Some(1024))));
+        assert!(handle.send(PatcherCommand::Quit));
+        let (new_exe_path, events) =
+            futures::join!(patcher.run(), event_stream.collect::<Vec<_>>());
+        assert!(new_exe_path.is_none());
+        assert!(events.is_empty());
+        // The patcher doesn't accept commands once it's stopped
+        assert!(!handle.cancel_update());
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use futures::stream::BoxStream;
+use serde::Serialize;
+
+use super::PatcherCommand;
+
+/// Events emitted by the patching engine, in the order in which they happen.
+///
+/// Events are serialized as JSON objects whose `type` field contains the
+/// snake_case name of the variant (e.g., `{"type": "patch_applied",
+/// "file_name": "patch1.thor"}`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PatcherEvent {
+    /// An update or a manual patch is in progress
+    PatchingStarted,
+    /// The update or the manual patch is over
+    PatchingStopped,
+    /// The update has succeeded
+    Ready,
+    Error(PatcherError),
+    DownloadInProgress(DownloadProgress),
+    /// A patch has been downloaded (or found in the download cache)
+    PatchDownloaded {
+        file_name: String,
+    },
+    InstallationInProgress {
+        installed_patches: usize,
+        total_patches: usize,
+    },
+    /// A patch has been applied during an update
+    PatchApplied {
+        file_name: String,
+    },
+    /// A patch submitted manually has been applied
+    ManualPatchApplied {
+        file_name: String,
+    },
+    /// Problem found in the patch list or in the manifest of the patch server
+    /// in use, with lenient parsing
+    PatchListWarning(PatchListWarning),
+    /// Names of the patch servers in use
+    PatchServersInUse {
+        names: Vec<String>,
+    },
+    /// The patcher is being updated to `version`
+    PatcherUpdateInProgress {
+        version: String,
+    },
+    /// The patcher has updated itself and must be restarted from `exe_path`
+    RelaunchRequired {
+        exe_path: PathBuf,
+    },
+}
+
+/// Progress of the downloads of an update.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DownloadProgress {
+    pub downloaded_patches: usize,
+    pub total_patches: usize,
+    pub bytes_per_sec: u64,
+    pub downloaded_bytes: u64, // Bytes of this update's patches downloaded so far
+    pub total_bytes: Option<u64>, // Size of this update's patches, if known
+    pub eta_secs: Option<u64>, // Estimated time left, if the total is known
+}
+
+/// Invalid entry that has been skipped or kept in a patch list or a manifest.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PatchListWarning {
+    pub line_number: Option<usize>, // Line of the 'plist.txt' file (none for manifests)
+    pub message: String,            // Human-readable description of the problem
+}
+
+impl fmt::Display for PatchListWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line_number {
+            Some(line_number) => write!(f, "line {}: {}", line_number, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Error that interrupted an update or a manual patch.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PatcherError {
+    pub code: ErrorCode,
+    pub message: String, // Human-readable description of the error
+}
+
+impl PatcherError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for PatcherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Machine-readable kind of a `PatcherError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UpdateLocked,    // Another instance of the patcher is updating the game
+    NoPatchServer,   // None of the patch servers could be used
+    PatcherOutdated, // A mandatory patch requires a newer version of the patcher
+    DownloadFailed,  // A patch couldn't be downloaded
+    PatchingFailed,  // A patch couldn't be applied
+    Canceled,        // The update has been canceled
+    Internal,        // Any other error (I/O errors, ...)
+}
+
+/// Stream of the events emitted by a `Patcher`.
+///
+/// The stream ends once the patcher has stopped.
+pub type PatcherEventStream = BoxStream<'static, PatcherEvent>;
+
+/// Sends commands to a `Patcher`.
+///
+/// Handles can be cloned and used from any thread.
+#[derive(Clone)]
+pub struct PatcherHandle {
+    commands_tx: flume::Sender<PatcherCommand>,
+}
+
+impl PatcherHandle {
+    /// Sends `command` to the patcher.
+    ///
+    /// Returns false if the patcher has stopped.
+    pub fn send(&self, command: PatcherCommand) -> bool {
+        self.commands_tx.send(command).is_ok()
+    }
+
+    /// Cancels the update in progress, if any.
+    pub fn cancel_update(&self) -> bool {
+        self.send(PatcherCommand::CancelUpdate)
+    }
+}
+
+/// Emits events to the consumer of a `Patcher`.
+pub(crate) struct EventSender {
+    events_tx: flume::Sender<PatcherEvent>,
+}
+
+impl EventSender {
+    pub fn send(&self, event: PatcherEvent) {
+        // Events are dropped if nobody's listening anymore
+        let _ = self.events_tx.send(event);
+    }
+}
+
+/// Creates the channels used to communicate with a `Patcher`.
+pub(crate) fn channels() -> (
+    (PatcherHandle, flume::Receiver<PatcherCommand>),
+    (EventSender, PatcherEventStream),
+) {
+    let (commands_tx, commands_rx) = flume::unbounded();
+    let (events_tx, events_rx) = flume::unbounded();
+    (
+        (PatcherHandle { commands_tx }, commands_rx),
+        (EventSender { events_tx }, Box::pin(events_rx.into_stream())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_serialization() {
+        assert_eq!(
+            r#"{"type":"ready"}"#,
+            serde_json::to_string(&PatcherEvent::Ready).unwrap()
+        );
+        assert_eq!(
+            r#"{"type":"error","code":"download_failed","message":"Failed"}"#,
+            serde_json::to_string(&PatcherEvent::Error(PatcherError::new(
+                ErrorCode::DownloadFailed,
+                "Failed"
+            )))
+            .unwrap()
+        );
+        assert_eq!(
+            r#"{"type":"download_in_progress","downloaded_patches":1,"total_patches":2,"bytes_per_sec":512,"downloaded_bytes":1024,"total_bytes":2048,"eta_secs":2}"#,
+            serde_json::to_string(&PatcherEvent::DownloadInProgress(DownloadProgress {
+                downloaded_patches: 1,
+                total_patches: 2,
+                bytes_per_sec: 512,
+                downloaded_bytes: 1024,
+                total_bytes: Some(2048),
+                eta_secs: Some(2),
+            }))
+            .unwrap()
+        );
+        assert_eq!(
+            r#"{"type":"patch_applied","file_name":"patch1.thor"}"#,
+            serde_json::to_string(&PatcherEvent::PatchApplied {
+                file_name: "patch1.thor".to_string()
+            })
+            .unwrap()
+        );
+    }
+}
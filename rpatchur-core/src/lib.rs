@@ -0,0 +1,59 @@
+//! Patching engine of rpatchur.
+//!
+//! The engine probes the configured patch servers, downloads the patches that
+//! haven't been applied yet and applies them. It's driven with a
+//! `PatcherHandle` and reports its progress through a stream of
+//! `PatcherEvent`:
+//!
+//! ```no_run
+//! # async fn example(config: rpatchur_core::EngineConfiguration) {
+//! use futures::StreamExt;
+//! use rpatchur_core::{Patcher, PatcherCommand};
+//!
+//! let (patcher, handle, mut events) = Patcher::new(config);
+//! handle.send(PatcherCommand::StartUpdate);
+//! let consumer = async move {
+//!     while let Some(event) = events.next().await {
+//!         println!("{:?}", event);
+//!     }
+//! };
+//! let (relaunch_required, _) = futures::join!(patcher.run(), consumer);
+//! # }
+//! ```
+//!
+//! The game client's directory and the files used by the engine (cache,
+//! download directory and lock) are set with `EngineConfiguration::paths`.
+
+mod cache;
+mod cancellation;
+mod config;
+mod download;
+mod download_cache;
+mod engine;
+mod events;
+mod manifest;
+mod patching;
+mod self_update;
+
+use std::path::PathBuf;
+
+pub use self::cache::AppliedPatch;
+pub use self::config::{
+    ClientConfiguration, EngineConfiguration, EnginePaths, PatchServerInfo, PatchingConfiguration,
+    WebConfiguration,
+};
+pub use self::engine::{get_patch_history, get_pending_patches, reset_cache, Patcher};
+pub use self::events::{
+    DownloadProgress, ErrorCode, PatchListWarning, PatcherError, PatcherEvent, PatcherEventStream,
+    PatcherHandle,
+};
+pub use self::self_update::{relaunch, remove_previous_exe};
+
+#[derive(Debug)]
+pub enum PatcherCommand {
+    StartUpdate,
+    CancelUpdate,                       // Canceled by the user
+    ApplyPatch(PathBuf),                // Manual patch submitted by the user
+    SetDownloadSpeedLimit(Option<u64>), // Bytes per second, None for unlimited
+    Quit,                               // Exit requested
+}
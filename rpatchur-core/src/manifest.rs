@@ -0,0 +1,306 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::{self, PatchListParsingMode, ThorPatchInfo, ThorPatchList};
+use serde::Deserialize;
+use url::Url;
+
+use super::events::PatchListWarning;
+use super::self_update::PatcherRelease;
+
+/// List of the patches served by a patch server, along with their details.
+///
+/// Manifests are JSON or YAML documents. Patch servers that don't provide one
+/// are described with the content of their 'plist.txt' file, in which case the
+/// patches' details are unknown.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PatchManifest {
+    pub patches: Vec<PatchManifestEntry>,
+    pub patcher: Option<PatcherRelease>, // Latest release of the patcher
+    #[serde(skip)]
+    pub warnings: Vec<PatchListWarning>, // Problems found while parsing, in lenient mode
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct PatchManifestEntry {
+    pub index: usize,
+    pub file_name: String,
+    pub size: Option<u64>,           // Size of the THOR archive in bytes
+    pub sha256: Option<String>,      // Hex-encoded hash of the THOR archive
+    pub url: Option<String>,         // Absolute URL to download the patch from
+    pub description: Option<String>, // Description of the patch's content
+    pub mandatory: Option<bool>,     // Optional patches can be skipped (true by default)
+    pub min_patcher_version: Option<String>, // Version of the patcher required to apply the patch
+}
+
+impl PatchManifest {
+    /// Parses a JSON or a YAML manifest and reports the problems found in it.
+    ///
+    /// Invalid hashes and URLs are errors. Like in patch lists, unsafe file
+    /// names as well as duplicate indices and file names are reported (with
+    /// the position of the patch in the list). They're errors in strict mode,
+    /// while in lenient mode patches with unsafe file names are skipped and
+    /// problems are returned along with the manifest.
+    pub fn from_string(content: &str, mode: PatchListParsingMode) -> Result<(Self, Vec<String>)> {
+        let mut manifest: PatchManifest = if content.trim_start().starts_with('{') {
+            serde_json::from_str(content).context("Invalid JSON manifest")?
+        } else {
+            serde_yaml::from_str(content).context("Invalid YAML manifest")?
+        };
+        let mut diagnostics = vec![];
+        let mut indices = HashSet::new();
+        let mut file_names = HashSet::new();
+        let mut patches = Vec::with_capacity(manifest.patches.len());
+        for (i, patch) in manifest.patches.into_iter().enumerate() {
+            let position = i + 1;
+            if let Some(sha256) = &patch.sha256 {
+                let is_valid_hash =
+                    sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit());
+                if !is_valid_hash {
+                    return Err(anyhow!("Invalid SHA-256 hash for '{}'", patch.file_name));
+                }
+            }
+            if let Some(url) = &patch.url {
+                Url::parse(url)
+                    .with_context(|| format!("Invalid URL for '{}'", patch.file_name))?;
+            }
+            // File names are joined to the download directory
+            if thor::check_file_name(&patch.file_name).is_err() {
+                diagnostics.push(format!(
+                    "patch {}: unsafe file name '{}'",
+                    position, patch.file_name
+                ));
+                continue;
+            }
+            if !indices.insert(patch.index) {
+                diagnostics.push(format!(
+                    "patch {}: duplicate patch index {}",
+                    position, patch.index
+                ));
+            }
+            if !file_names.insert(patch.file_name.clone()) {
+                diagnostics.push(format!(
+                    "patch {}: duplicate file name '{}'",
+                    position, patch.file_name
+                ));
+            }
+            patches.push(patch);
+        }
+        if mode == PatchListParsingMode::Strict && !diagnostics.is_empty() {
+            return Err(anyhow!("Invalid manifest: {}", diagnostics.join(", ")));
+        }
+        // Sort patch list by index
+        patches.sort_by_key(|patch| patch.index);
+        manifest.patches = patches;
+        Ok((manifest, diagnostics))
+    }
+
+    /// Builds a manifest without details out of the content of a 'plist.txt'
+    /// file.
+    pub fn from_patch_list(patch_list: ThorPatchList) -> Self {
+        Self {
+            patches: patch_list
+                .into_iter()
+                .map(|patch| PatchManifestEntry {
+                    index: patch.index,
+                    file_name: patch.file_name,
+                    size: None,
+                    sha256: None,
+                    url: None,
+                    description: None,
+                    mandatory: None,
+                    min_patcher_version: None,
+                })
+                .collect(),
+            patcher: None,
+            warnings: vec![],
+        }
+    }
+
+    /// Returns the list of patches described by the manifest.
+    pub fn patch_list(&self) -> ThorPatchList {
+        self.patches
+            .iter()
+            .map(|patch| ThorPatchInfo {
+                index: patch.index,
+                file_name: patch.file_name.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns the details of `patch`, if it's part of the manifest.
+    pub fn get(&self, patch: &ThorPatchInfo) -> Option<&PatchManifestEntry> {
+        self.patches
+            .iter()
+            .find(|entry| entry.index == patch.index && entry.file_name == patch.file_name)
+    }
+
+    /// Returns the hash of `patch`, if the manifest contains it.
+    pub fn sha256(&self, patch: &ThorPatchInfo) -> Option<&str> {
+        self.get(patch).and_then(|entry| entry.sha256.as_deref())
+    }
+}
+
+impl PatchManifestEntry {
+    /// Returns the URL the patch should be downloaded from, given the URL of
+    /// the directory containing the patches of a patch server.
+    pub fn download_url(&self, patch_url: &Url) -> Result<Url> {
+        match &self.url {
+            Some(url) => Url::parse(url).with_context(|| "Invalid patch URL"),
+            None => patch_url.join(&self.file_name).with_context(|| {
+                format!(
+                    "Invalid file name '{}' given in patch list file",
+                    self.file_name
+                )
+            }),
+        }
+    }
+
+    /// Returns true if the patch must be applied for the update to succeed.
+    pub fn is_mandatory(&self) -> bool {
+        self.mandatory.unwrap_or(true)
+    }
+
+    /// Returns true if the patch can be applied by this version of the
+    /// patcher.
+    pub fn is_supported(&self) -> bool {
+        match &self.min_patcher_version {
+            None => true,
+            Some(min_version) => {
+                compare_versions(env!("CARGO_PKG_VERSION"), min_version) != Ordering::Less
+            }
+        }
+    }
+}
+
+/// Compares two dot-separated version numbers (e.g., "0.3.0" and "0.4").
+///
+/// Missing components are considered to be 0 and non-numeric suffixes are
+/// ignored.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parse = |version: &str| -> Vec<u64> {
+        let mut components: Vec<u64> = version
+            .trim()
+            .trim_start_matches('v')
+            .split('.')
+            .map(|component| {
+                let digits: String = component
+                    .chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect();
+                digits.parse().unwrap_or(0)
+            })
+            .collect();
+        while components.last() == Some(&0) {
+            components.pop();
+        }
+        components
+    };
+    parse(a).cmp(&parse(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_manifest() {
+        let (json_manifest, _) = PatchManifest::from_string(
+            r#"{"patches": [
+                {"index": 2, "file_name": "patch2.thor", "mandatory": false,
+                 "url": "https://cdn.example.com/patch2.thor"},
+                {"index": 1, "file_name": "patch1.thor", "size": 1024,
+                 "sha256": "5f70bf18a086007016e948b04aed3b82103a36bea41755b6cddfaf10ace3c6ef"}
+            ]}"#,
+            PatchListParsingMode::Strict,
+        )
+        .unwrap();
+        let (yaml_manifest, _) = PatchManifest::from_string(
+            "patches:
+  - index: 1
+    file_name: patch1.thor
+    size: 1024
+    sha256: 5f70bf18a086007016e948b04aed3b82103a36bea41755b6cddfaf10ace3c6ef
+  - index: 2
+    file_name: patch2.thor
+    url: https://cdn.example.com/patch2.thor
+    mandatory: false
+",
+            PatchListParsingMode::Strict,
+        )
+        .unwrap();
+        assert_eq!(json_manifest, yaml_manifest);
+        assert_eq!(
+            vec![1, 2],
+            json_manifest
+                .patch_list()
+                .iter()
+                .map(|p| p.index)
+                .collect::<Vec<_>>()
+        );
+        let patch_url = Url::parse("https://example.com/data/").unwrap();
+        let patches = &json_manifest.patches;
+        assert!(patches[0].is_mandatory());
+        assert!(!patches[1].is_mandatory());
+        assert_eq!(
+            "https://example.com/data/patch1.thor",
+            patches[0].download_url(&patch_url).unwrap().as_str()
+        );
+        assert_eq!(
+            "https://cdn.example.com/patch2.thor",
+            patches[1].download_url(&patch_url).unwrap().as_str()
+        );
+
+        assert!(PatchManifest::from_string(
+            r#"{"patches": [{"index": 1, "file_name": "patch1.thor", "sha256": "1234"}]}"#,
+            PatchListParsingMode::Lenient
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_patch_manifest_diagnostics() {
+        for file_name in ["../../game.exe", "/tmp/patch.thor", "C:patch.thor"].iter() {
+            let content = format!(
+                r#"{{"patches": [{{"index": 1, "file_name": "{}"}}]}}"#,
+                file_name
+            );
+            assert!(
+                PatchManifest::from_string(&content, PatchListParsingMode::Strict).is_err(),
+                "{}",
+                file_name
+            );
+            let (manifest, diagnostics) =
+                PatchManifest::from_string(&content, PatchListParsingMode::Lenient).unwrap();
+            assert!(manifest.patches.is_empty(), "{}", file_name);
+            assert_eq!(1, diagnostics.len());
+        }
+
+        let content = r#"{"patches": [
+            {"index": 1, "file_name": "patch1.thor"},
+            {"index": 1, "file_name": "patch2.thor"},
+            {"index": 3, "file_name": "patch1.thor"}
+        ]}"#;
+        assert!(PatchManifest::from_string(content, PatchListParsingMode::Strict).is_err());
+        let (manifest, diagnostics) =
+            PatchManifest::from_string(content, PatchListParsingMode::Lenient).unwrap();
+        // Duplicates are kept, like in patch lists
+        assert_eq!(3, manifest.patches.len());
+        assert_eq!(
+            vec![
+                "patch 2: duplicate patch index 1".to_string(),
+                "patch 3: duplicate file name 'patch1.thor'".to_string()
+            ],
+            diagnostics
+        );
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(Ordering::Equal, compare_versions("0.3.0", "0.3"));
+        assert_eq!(Ordering::Less, compare_versions("0.3.0", "0.10.0"));
+        assert_eq!(Ordering::Greater, compare_versions("1.0.0", "v0.9.9"));
+        assert_eq!(Ordering::Greater, compare_versions("0.4.0-beta", "0.3.2"));
+    }
+}
@@ -0,0 +1,735 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use gruf::grf::{GrfArchive, GrfArchiveBuilder};
+use gruf::thor::{self, ThorArchive, ThorFileEntry};
+
+// Extensions of the temporary files created when patching the game's directory
+const STAGED_FILE_EXTENSION: &str = "rpatchur-new";
+const BACKUP_FILE_EXTENSION: &str = "rpatchur-bak";
+
+/// Indicates the method that should be used when patching GRF files.
+pub enum GrfPatchingMethod {
+    OutOfPlace,
+    InPlace,
+}
+
+/// Indicates the type of archive a "file" comes from.
+enum MergeEntrySource {
+    GrfArchive,
+    ThorArchive(usize), // Index of the THOR archive
+}
+
+/// Indicates the transformation that should be applied to the data when copied
+/// from a GRF file to another.
+enum DataTransformation {
+    None,
+    // DecompressZlib,
+}
+
+struct MergeEntry {
+    pub source: MergeEntrySource,
+    pub source_offset: u64,
+    pub data_size: usize,
+    pub transformation: DataTransformation,
+}
+
+/// Patches a GRF file with several THOR archives/patches at once.
+///
+/// Archives must be given in the order they should be applied in. Entries are
+/// coalesced so that each one of them is written only once.
+pub fn apply_patches_to_grf<R: Read + Seek>(
+    patching_method: GrfPatchingMethod,
+    create_if_needed: bool,
+    grf_file_path: impl AsRef<Path>,
+    thor_archives: &mut [ThorArchive<R>],
+) -> Result<()> {
+    if !grf_file_path.as_ref().exists() && create_if_needed {
+        // Create a new GRF file if needed
+        let new_grf = fs::File::create(&grf_file_path)?;
+        GrfArchiveBuilder::create(new_grf, 2, 0)?;
+    }
+    match patching_method {
+        GrfPatchingMethod::InPlace => apply_patches_to_grf_ip(grf_file_path, thor_archives),
+        GrfPatchingMethod::OutOfPlace => apply_patches_to_grf_oop(grf_file_path, thor_archives),
+    }
+}
+
+/// Computes the final state of the entries contained in a list of THOR
+/// archives. Entries from later archives replace entries from earlier ones.
+///
+/// Returns the final entries along with the index of the archive they come
+/// from, sorted by archive and by offset.
+fn coalesce_entries<R: Read + Seek>(
+    thor_archives: &[ThorArchive<R>],
+) -> Vec<(usize, ThorFileEntry)> {
+    let mut final_entries: HashMap<String, (usize, ThorFileEntry)> = HashMap::new();
+    for (archive_index, thor_archive) in thor_archives.iter().enumerate() {
+        for entry in thor_archive.get_entries().filter(|e| !e.is_internal()) {
+            final_entries.insert(entry.relative_path.clone(), (archive_index, entry.clone()));
+        }
+    }
+    let mut final_entries: Vec<(usize, ThorFileEntry)> = final_entries.values().cloned().collect();
+    final_entries.sort_unstable_by(|a, b| (a.0, a.1.offset).cmp(&(b.0, b.1.offset)));
+    final_entries
+}
+
+/// Patches a GRF in an in-place manner.
+///
+/// This is faster but produces output of bigger size and can corrupt file in
+/// case of error.
+fn apply_patches_to_grf_ip<R: Read + Seek>(
+    grf_file_path: impl AsRef<Path>,
+    thor_archives: &mut [ThorArchive<R>],
+) -> Result<()> {
+    let mut builder = GrfArchiveBuilder::open(grf_file_path)?;
+    for (archive_index, entry) in coalesce_entries(thor_archives) {
+        if entry.is_removed {
+            let _ = builder.remove_file(&entry.relative_path);
+        } else {
+            builder.import_raw_entry_from_thor(
+                &mut thor_archives[archive_index],
+                entry.relative_path,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Patches a GRF in an out-of-place manner.
+///
+/// This is safer and produces output of smaller size but slower.
+fn apply_patches_to_grf_oop<R: Read + Seek>(
+    grf_file_path: impl AsRef<Path>,
+    thor_archives: &mut [ThorArchive<R>],
+) -> Result<()> {
+    // Rename file to back it up
+    let mut backup_file_path = grf_file_path.as_ref().to_path_buf();
+    backup_file_path.set_extension("grf.bak");
+    fs::rename(grf_file_path.as_ref(), &backup_file_path)?;
+
+    // Prepare file entries that'll be used to make the patched GRF
+    let thor_entries = coalesce_entries(thor_archives);
+    let mut merge_entries: HashMap<String, MergeEntry> = HashMap::new();
+    // Add files from the original archive while discarding files remove in the patches
+    let mut grf_archive = GrfArchive::open(&backup_file_path)?;
+    for entry in grf_archive.get_entries() {
+        merge_entries.insert(
+            entry.relative_path.clone(),
+            MergeEntry {
+                source: MergeEntrySource::GrfArchive,
+                source_offset: entry.offset,
+                data_size: entry.size_compressed,
+                transformation: DataTransformation::None,
+            },
+        );
+    }
+    // Add files from the patches
+    for (archive_index, entry) in thor_entries {
+        if entry.is_removed {
+            merge_entries.remove(&entry.relative_path);
+            continue;
+        }
+        merge_entries.insert(
+            entry.relative_path.clone(),
+            MergeEntry {
+                source: MergeEntrySource::ThorArchive(archive_index),
+                source_offset: entry.offset,
+                data_size: entry.size_compressed,
+                transformation: DataTransformation::None,
+            },
+        );
+    }
+
+    {
+        let grf_file = fs::File::create(grf_file_path)?;
+        let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0)?;
+        for (relative_path, entry) in merge_entries {
+            match entry.source {
+                MergeEntrySource::GrfArchive => {
+                    builder.import_raw_entry_from_grf(&mut grf_archive, relative_path)?;
+                }
+                MergeEntrySource::ThorArchive(archive_index) => {
+                    builder.import_raw_entry_from_thor(
+                        &mut thor_archives[archive_index],
+                        relative_path,
+                    )?;
+                }
+            }
+        }
+    }
+    // Remove backup file once the patched GRF has been built
+    Ok(fs::remove_file(backup_file_path)?)
+}
+
+/// Patches files located in the game client's directory with several THOR
+/// archives/patches at once.
+///
+/// Archives must be given in the order they should be applied in. Entries are
+/// coalesced so that each file is written only once.
+///
+/// Changes are applied transactionally: files are extracted next to their
+/// targets first, files that are replaced or removed are backed up and then
+/// everything is renamed into place. If anything fails, all the changes are
+/// reverted. Changes left by an application that couldn't be reverted (e.g.,
+/// because the patcher crashed) are reverted before applying new patches.
+pub fn apply_patches_to_disk<R: Read + Seek>(
+    root_directory: impl AsRef<Path>,
+    thor_archives: &mut [ThorArchive<R>],
+) -> Result<()> {
+    // TODO(LinkZ): Make async?
+    // Make sure no entry can be written outside of the root directory before
+    // touching anything
+    for thor_archive in thor_archives.iter() {
+        for entry in thor_archive.get_entries().filter(|e| !e.is_internal()) {
+            thor::check_relative_path(&entry.relative_path)
+                .with_context(|| format!("Refusing to patch '{}'", entry.relative_path))?;
+        }
+    }
+    recover_interrupted_transaction(root_directory.as_ref())
+        .with_context(|| "Failed to revert a previous update")?;
+    let file_entries = coalesce_entries(thor_archives);
+
+    let mut transaction = DiskTransaction::default();
+    let result = apply_file_entries(
+        root_directory.as_ref(),
+        thor_archives,
+        file_entries,
+        &mut transaction,
+    );
+    match result {
+        Ok(()) => {
+            transaction.commit();
+            Ok(())
+        }
+        Err(e) => {
+            transaction.rollback();
+            Err(e)
+        }
+    }
+}
+
+/// Stages, backs up and renames files into place while recording every change
+/// into `transaction`.
+fn apply_file_entries<R: Read + Seek>(
+    root_directory: &Path,
+    thor_archives: &mut [ThorArchive<R>],
+    file_entries: Vec<(usize, ThorFileEntry)>,
+    transaction: &mut DiskTransaction,
+) -> Result<()> {
+    // Extract updated files next to their targets
+    let mut targets: Vec<(PathBuf, Option<PathBuf>)> = Vec::with_capacity(file_entries.len());
+    for (archive_index, entry) in file_entries {
+        let dest_path = join_windows_relative_path(root_directory, &entry.relative_path);
+        if entry.is_removed {
+            targets.push((dest_path, None));
+            continue;
+        }
+        // Create parent directory if needed
+        if let Some(parent_dir) = dest_path.parent() {
+            transaction.create_dir_all(parent_dir)?;
+        }
+        let staged_path = append_extension(&dest_path, STAGED_FILE_EXTENSION);
+        transaction.staged_files.push(staged_path.clone());
+        thor_archives[archive_index]
+            .extract_file(&entry.relative_path, &staged_path)
+            .with_context(|| format!("Failed to extract '{}'", entry.relative_path))?;
+        targets.push((dest_path, Some(staged_path)));
+    }
+    // Back up the files that are going to be replaced or removed
+    for (dest_path, _) in &targets {
+        if dest_path.is_file() {
+            let backup_path = append_extension(dest_path, BACKUP_FILE_EXTENSION);
+            fs::rename(dest_path, &backup_path)
+                .with_context(|| format!("Failed to back up '{}'", dest_path.to_string_lossy()))?;
+            transaction
+                .backed_up_files
+                .push((backup_path, dest_path.clone()));
+        }
+    }
+    // Move the updated files into place
+    for (dest_path, staged_path) in targets {
+        if let Some(staged_path) = staged_path {
+            fs::rename(&staged_path, &dest_path)
+                .with_context(|| format!("Failed to update '{}'", dest_path.to_string_lossy()))?;
+            transaction.staged_files.retain(|p| p != &staged_path);
+            transaction.updated_files.push(dest_path);
+        }
+    }
+    Ok(())
+}
+
+/// Keeps track of the changes made to the game client's directory so that they
+/// can be reverted.
+#[derive(Default)]
+struct DiskTransaction {
+    created_directories: Vec<PathBuf>,
+    staged_files: Vec<PathBuf>,
+    backed_up_files: Vec<(PathBuf, PathBuf)>, // Backup path, Original path
+    updated_files: Vec<PathBuf>,
+}
+
+impl DiskTransaction {
+    /// Creates a directory and its parents while keeping track of the
+    /// directories that didn't exist.
+    fn create_dir_all(&mut self, dir_path: &Path) -> Result<()> {
+        let mut missing_directories: Vec<PathBuf> = dir_path
+            .ancestors()
+            .take_while(|p| !p.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        if missing_directories.is_empty() {
+            return Ok(());
+        }
+        missing_directories.reverse();
+        fs::create_dir_all(dir_path)?;
+        self.created_directories.extend(missing_directories);
+        Ok(())
+    }
+
+    /// Removes backups once all the changes have been applied.
+    fn commit(self) {
+        for (backup_path, _) in self.backed_up_files {
+            if let Err(e) = fs::remove_file(&backup_path) {
+                log::warn!(
+                    "Failed to remove backup '{}': {}",
+                    backup_path.to_string_lossy(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Reverts all the changes, in reverse order.
+    fn rollback(self) {
+        for updated_path in self.updated_files.iter().rev() {
+            let _ignore = fs::remove_file(updated_path);
+        }
+        for (backup_path, original_path) in self.backed_up_files.iter().rev() {
+            if let Err(e) = fs::rename(backup_path, original_path) {
+                log::error!(
+                    "Failed to restore '{}': {}",
+                    original_path.to_string_lossy(),
+                    e
+                );
+            }
+        }
+        for staged_path in self.staged_files.iter().rev() {
+            let _ignore = fs::remove_file(staged_path);
+        }
+        for dir_path in self.created_directories.iter().rev() {
+            // Only removes empty directories
+            let _ignore = fs::remove_dir(dir_path);
+        }
+    }
+}
+
+/// Reverts the changes left in `root_directory` by a transaction that was
+/// interrupted: backups are restored and staged files are removed.
+fn recover_interrupted_transaction(root_directory: &Path) -> Result<()> {
+    if !root_directory.is_dir() {
+        return Ok(());
+    }
+    let mut directories = vec![root_directory.to_path_buf()];
+    while let Some(dir_path) = directories.pop() {
+        let entries = fs::read_dir(&dir_path)
+            .with_context(|| format!("Failed to read '{}'", dir_path.to_string_lossy()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            // Symbolic links aren't followed
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                directories.push(path);
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some(BACKUP_FILE_EXTENSION) => {
+                    let original_path = path.with_extension("");
+                    log::warn!("Restoring '{}'", original_path.to_string_lossy());
+                    fs::rename(&path, &original_path).with_context(|| {
+                        format!("Failed to restore '{}'", original_path.to_string_lossy())
+                    })?;
+                }
+                Some(STAGED_FILE_EXTENSION) => {
+                    fs::remove_file(&path).with_context(|| {
+                        format!("Failed to remove '{}'", path.to_string_lossy())
+                    })?;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appends an extension to a path without replacing its current extension.
+pub fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(extension);
+    path.with_file_name(file_name)
+}
+
+/// Utility function used to join path-like segments the same way it's done in
+/// the GRF file format (Windows style).
+fn join_windows_relative_path(path: &Path, windows_relative_path: &str) -> PathBuf {
+    let mut result = PathBuf::from(path);
+    for component in windows_relative_path.split('\\') {
+        result.push(component);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::ThorArchiveBuilder;
+    use tempfile::tempdir;
+    use walkdir::WalkDir;
+
+    #[test]
+    fn test_apply_patch_to_disk() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        {
+            let count_files = |dir_path| {
+                WalkDir::new(dir_path)
+                    .into_iter()
+                    .filter_map(|e| e.ok())
+                    .filter_map(|entry| entry.metadata().ok())
+                    .filter(|metadata| metadata.is_file())
+                    .count()
+            };
+            let expected_file_path = temp_dir
+                .path()
+                .join("data/wav/se_subterranean_rustyengine.wav");
+            let thor_archive_path = thor_dir_path.join("small.thor");
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+
+            // Before patching
+            assert!(!expected_file_path.exists());
+            assert_eq!(0, count_files(temp_dir.path()));
+
+            apply_patches_to_disk(temp_dir.path(), std::slice::from_mut(&mut thor_archive))
+                .unwrap();
+
+            // After patching
+            assert!(expected_file_path.exists());
+            assert_eq!(nb_of_added_files, count_files(temp_dir.path()));
+            // TODO(LinkZ): Check content
+        }
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_unsafe_path() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let thor_archive_path = temp_dir.path().join("unsafe.thor");
+        {
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update("data\\safe.txt".to_string(), [1_u8].as_ref())
+                .unwrap();
+            builder
+                .append_file_update("..\\evil.txt".to_string(), [2_u8].as_ref())
+                .unwrap();
+        }
+        fs::create_dir(&root_dir).unwrap();
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        assert!(apply_patches_to_disk(&root_dir, std::slice::from_mut(&mut thor_archive)).is_err());
+        // Nothing should have been written
+        assert!(!root_dir.join("data").exists());
+        assert!(!temp_dir.path().join("evil.txt").exists());
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_rollback() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path();
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        {
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update("data\\existing.txt".to_string(), b"new".as_ref())
+                .unwrap();
+            builder
+                .append_file_update("newdir\\new.txt".to_string(), b"new".as_ref())
+                .unwrap();
+            builder.append_file_removal("data\\removed.txt".to_string());
+            // Can't be replaced since it's a directory
+            builder
+                .append_file_update("data\\blocker".to_string(), b"new".as_ref())
+                .unwrap();
+        }
+        fs::create_dir_all(root_dir.join("data/blocker")).unwrap();
+        fs::write(root_dir.join("data/existing.txt"), b"old").unwrap();
+        fs::write(root_dir.join("data/removed.txt"), b"old").unwrap();
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        assert!(apply_patches_to_disk(root_dir, std::slice::from_mut(&mut thor_archive)).is_err());
+
+        // Everything should have been reverted
+        assert_eq!(
+            b"old",
+            &fs::read(root_dir.join("data/existing.txt")).unwrap()[..]
+        );
+        assert_eq!(
+            b"old",
+            &fs::read(root_dir.join("data/removed.txt")).unwrap()[..]
+        );
+        assert!(root_dir.join("data/blocker").is_dir());
+        assert!(!root_dir.join("newdir").exists());
+        assert_eq!(3, fs::read_dir(root_dir.join("data")).unwrap().count());
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_recovery() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        {
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update("data\\patched.txt".to_string(), b"new".as_ref())
+                .unwrap();
+        }
+        // Files left by an update that was interrupted
+        fs::create_dir_all(root_dir.join("data/sub")).unwrap();
+        fs::write(root_dir.join("data/replaced.txt"), b"new").unwrap();
+        fs::write(root_dir.join("data/replaced.txt.rpatchur-bak"), b"old").unwrap();
+        fs::write(root_dir.join("data/sub/removed.txt.rpatchur-bak"), b"old").unwrap();
+        fs::write(root_dir.join("data/sub/staged.txt.rpatchur-new"), b"new").unwrap();
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        apply_patches_to_disk(&root_dir, std::slice::from_mut(&mut thor_archive)).unwrap();
+
+        assert_eq!(
+            b"old",
+            &fs::read(root_dir.join("data/replaced.txt")).unwrap()[..]
+        );
+        assert_eq!(
+            b"old",
+            &fs::read(root_dir.join("data/sub/removed.txt")).unwrap()[..]
+        );
+        assert_eq!(
+            b"new",
+            &fs::read(root_dir.join("data/patched.txt")).unwrap()[..]
+        );
+        // Nothing else is left
+        assert_eq!(3, fs::read_dir(root_dir.join("data")).unwrap().count());
+        assert_eq!(1, fs::read_dir(root_dir.join("data/sub")).unwrap().count());
+    }
+
+    #[test]
+    fn test_apply_patches_coalesced() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        let thor_archive_paths = [
+            temp_dir.path().join("patch1.thor"),
+            temp_dir.path().join("patch2.thor"),
+        ];
+        {
+            let thor_file = fs::File::create(&thor_archive_paths[0]).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update("data\\updated.txt".to_string(), b"1".as_ref())
+                .unwrap();
+            builder
+                .append_file_update("data\\removed.txt".to_string(), b"1".as_ref())
+                .unwrap();
+        }
+        {
+            let thor_file = fs::File::create(&thor_archive_paths[1]).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update("data\\updated.txt".to_string(), b"2".as_ref())
+                .unwrap();
+            builder.append_file_removal("data\\removed.txt".to_string());
+        }
+        let open_archives = || -> Vec<ThorArchive<fs::File>> {
+            thor_archive_paths
+                .iter()
+                .map(|p| ThorArchive::open(p).unwrap())
+                .collect()
+        };
+
+        // Game directory
+        apply_patches_to_disk(&root_dir, &mut open_archives()).unwrap();
+        assert_eq!(
+            b"2",
+            &fs::read(root_dir.join("data/updated.txt")).unwrap()[..]
+        );
+        assert!(!root_dir.join("data/removed.txt").exists());
+
+        // GRF
+        fs::copy(grf_dir_path.join("200-empty.grf"), &grf_archive_path).unwrap();
+        apply_patches_to_grf(
+            GrfPatchingMethod::OutOfPlace,
+            false,
+            &grf_archive_path,
+            &mut open_archives(),
+        )
+        .unwrap();
+        let mut grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+        assert_eq!(1, grf_archive.file_count());
+        assert_eq!(
+            b"2",
+            &grf_archive.read_file_content("data\\updated.txt").unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_ip_empty() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        {
+            fs::copy(grf_dir_path.join("200-empty.grf"), &grf_archive_path).unwrap();
+
+            // Before patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(0, grf_archive.file_count());
+            let grf_version_major = grf_archive.version_major();
+            let grf_version_minor = grf_archive.version_minor();
+
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patches_to_grf(
+                GrfPatchingMethod::InPlace,
+                false,
+                &grf_archive_path,
+                std::slice::from_mut(&mut thor_archive),
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_added_files, grf_archive.file_count());
+            assert_eq!(grf_archive.version_major(), grf_version_major);
+            assert_eq!(grf_archive.version_minor(), grf_version_minor);
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_ip_empty_create() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        {
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patches_to_grf(
+                GrfPatchingMethod::InPlace,
+                true,
+                &grf_archive_path,
+                std::slice::from_mut(&mut thor_archive),
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_added_files, grf_archive.file_count());
+            assert_eq!(grf_archive.version_major(), 2);
+            assert_eq!(grf_archive.version_minor(), 0);
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_oop_empty() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        {
+            fs::copy(grf_dir_path.join("200-empty.grf"), &grf_archive_path).unwrap();
+
+            // Before patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(0, grf_archive.file_count());
+            let grf_version_major = grf_archive.version_major();
+            let grf_version_minor = grf_archive.version_minor();
+
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patches_to_grf(
+                GrfPatchingMethod::OutOfPlace,
+                false,
+                &grf_archive_path,
+                std::slice::from_mut(&mut thor_archive),
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_added_files, grf_archive.file_count());
+            assert_eq!(grf_archive.version_major(), grf_version_major);
+            assert_eq!(grf_archive.version_minor(), grf_version_minor);
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_oop_empty_create() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        {
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patches_to_grf(
+                GrfPatchingMethod::OutOfPlace,
+                true,
+                &grf_archive_path,
+                std::slice::from_mut(&mut thor_archive),
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_added_files, grf_archive.file_count());
+            assert_eq!(grf_archive.version_major(), 2);
+            assert_eq!(grf_archive.version_minor(), 0);
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    fn patch_maintained_integrity(
+        thor_file_path: &PathBuf,
+        grf_file_path: &PathBuf,
+    ) -> Result<bool> {
+        let mut thor_archive = ThorArchive::open(&thor_file_path)?;
+        let mut grf_archive = GrfArchive::open(&grf_file_path)?;
+        let thor_entries: Vec<ThorFileEntry> = thor_archive.get_entries().cloned().collect();
+        for file_entry in thor_entries {
+            if file_entry.is_internal() || file_entry.is_removed {
+                continue;
+            }
+            let expected_content = thor_archive.read_file_content(&file_entry.relative_path)?;
+            let file_content = grf_archive.read_file_content(&file_entry.relative_path)?;
+            if expected_content != file_content {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
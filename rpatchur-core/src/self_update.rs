@@ -0,0 +1,343 @@
+use std::cmp::Ordering;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use super::download::DownloadRateLimiter;
+use super::manifest::compare_versions;
+use super::patching::append_extension;
+
+const NEW_EXE_EXTENSION: &str = "new";
+const OLD_EXE_EXTENSION: &str = "old";
+
+/// Latest release of the patcher, as described by the patcher version
+/// endpoint (or by the patch manifest).
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct PatcherRelease {
+    pub version: String,           // Version of the release (e.g., "0.4.0")
+    pub url: String,               // URL of the release's executable
+    pub sha256: String,            // Hex-encoded hash of the release's executable
+    pub signature: Option<String>, // Hex-encoded Ed25519 signature of the executable's (raw) SHA-256 hash
+}
+
+impl PatcherRelease {
+    /// Returns true if the release is newer than the running patcher.
+    pub fn is_newer(&self) -> bool {
+        compare_versions(&self.version, env!("CARGO_PKG_VERSION")) == Ordering::Greater
+    }
+}
+
+/// Downloads and parses the JSON or YAML document served by the patcher
+/// version endpoint.
+pub async fn fetch_patcher_release(
+    client: &reqwest::Client,
+    release_url: &str,
+) -> Result<PatcherRelease> {
+    let release_url =
+        Url::parse(release_url).with_context(|| "Failed to parse 'patcher_update_url'")?;
+    let resp = client
+        .get(release_url)
+        .send()
+        .await
+        .with_context(|| "Failed to GET URL")?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("Patcher version not found on the remote server"));
+    }
+    let content = resp.text().await.with_context(|| "Invalid response body")?;
+    if content.trim_start().starts_with('{') {
+        serde_json::from_str(&content).context("Invalid JSON patcher release")
+    } else {
+        serde_yaml::from_str(&content).context("Invalid YAML patcher release")
+    }
+}
+
+/// Downloads `release` next to `exe_path` and checks its hash.
+///
+/// The hash only guarantees the executable's integrity, as it's served by the
+/// same server. When `trusted_public_keys` isn't empty, the release must also
+/// have been signed with one of these keys.
+///
+/// Returns the path of the downloaded executable.
+pub async fn download_patcher_release(
+    client: &reqwest::Client,
+    release: &PatcherRelease,
+    exe_path: &Path,
+    trusted_public_keys: &[String],
+    rate_limiter: &DownloadRateLimiter,
+) -> Result<PathBuf> {
+    let release_url = Url::parse(&release.url).with_context(|| "Invalid release URL")?;
+    let new_exe_path = append_extension(exe_path, NEW_EXE_EXTENSION);
+    let mut resp = client
+        .get(release_url)
+        .send()
+        .await
+        .with_context(|| "Failed to download the new patcher")?
+        .error_for_status()?;
+
+    let mut file = File::create(&new_exe_path)
+        .await
+        .with_context(|| format!("Failed to create '{}'", new_exe_path.display()))?;
+    let mut hasher = Sha256::new();
+    let result: Result<()> = async {
+        while let Some(chunk) = resp.chunk().await? {
+            rate_limiter.consume(chunk.len() as u64).await;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        Ok(())
+    }
+    .await;
+    drop(file);
+    let digest = hasher.finalize();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&new_exe_path).await;
+        return Err(e.context("Failed to download the new patcher"));
+    }
+    if !hex::encode(digest).eq_ignore_ascii_case(&release.sha256) {
+        let _ = fs::remove_file(&new_exe_path).await;
+        return Err(anyhow!("The new patcher's hash doesn't match"));
+    }
+    if !trusted_public_keys.is_empty() {
+        if let Err(e) = check_release_signature(release, &digest, trusted_public_keys) {
+            let _ = fs::remove_file(&new_exe_path).await;
+            return Err(e);
+        }
+    }
+    set_executable_permissions(&new_exe_path).await?;
+
+    Ok(new_exe_path)
+}
+
+/// Checks that the executable whose hash is `digest` has been signed with one
+/// of the given public keys.
+fn check_release_signature(
+    release: &PatcherRelease,
+    digest: &[u8],
+    trusted_public_keys: &[String],
+) -> Result<()> {
+    let signature = release
+        .signature
+        .as_ref()
+        .ok_or_else(|| anyhow!("The new patcher isn't signed"))?;
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|signature| Signature::from_bytes(&signature).ok())
+        .ok_or_else(|| anyhow!("The new patcher's signature is invalid"))?;
+    for public_key in trusted_public_keys {
+        let public_key = hex::decode(public_key)
+            .ok()
+            .and_then(|public_key| PublicKey::from_bytes(&public_key).ok())
+            .ok_or_else(|| anyhow!("Invalid public key in 'trusted_public_keys'"))?;
+        if public_key.verify(digest, &signature).is_ok() {
+            return Ok(());
+        }
+    }
+    Err(anyhow!(
+        "The new patcher hasn't been signed by a trusted key"
+    ))
+}
+
+/// Replaces the executable at `exe_path` with `new_exe_path`.
+///
+/// Running executables cannot be overwritten on Windows but they can be
+/// renamed, so the current executable is moved out of the way first (and
+/// removed by `remove_previous_exe` on the next start).
+pub fn replace_exe(exe_path: &Path, new_exe_path: &Path) -> Result<()> {
+    let old_exe_path = append_extension(exe_path, OLD_EXE_EXTENSION);
+    match std::fs::remove_file(&old_exe_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(anyhow!(
+                "Failed to remove '{}': {}",
+                old_exe_path.display(),
+                e
+            ));
+        }
+        _ => {}
+    }
+    std::fs::rename(exe_path, &old_exe_path)
+        .with_context(|| "Failed to move the current patcher out of the way")?;
+    if let Err(e) = std::fs::rename(new_exe_path, exe_path) {
+        // Put the current executable back in place
+        let _ = std::fs::rename(&old_exe_path, exe_path);
+        return Err(anyhow!("Failed to install the new patcher: {}", e));
+    }
+    Ok(())
+}
+
+/// Removes the executable left by a previous self-update, if any.
+pub fn remove_previous_exe() {
+    if let Ok(exe_path) = env::current_exe() {
+        let old_exe_path = append_extension(&exe_path, OLD_EXE_EXTENSION);
+        if let Err(e) = std::fs::remove_file(&old_exe_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("Failed to remove '{}': {}.", old_exe_path.display(), e);
+            }
+        }
+    }
+}
+
+/// Starts the updated patcher at `exe_path` with the given CLI arguments, from
+/// `working_directory`.
+///
+/// `exe_path` must be the path the new patcher has been installed at (as
+/// given by `PatcherEvent::RelaunchRequired`). `env::current_exe` cannot be
+/// used here: it follows the running executable, which has been moved out of
+/// the way.
+pub fn relaunch(
+    exe_path: &Path,
+    working_directory: &Path,
+    arguments: Vec<OsString>,
+) -> Result<Child> {
+    Command::new(exe_path)
+        .args(arguments)
+        .current_dir(working_directory)
+        .spawn()
+        .with_context(|| "Failed to start the new patcher")
+}
+
+#[cfg(unix)]
+async fn set_executable_permissions(exe_path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(exe_path, std::fs::Permissions::from_mode(0o755))
+        .await
+        .with_context(|| "Failed to make the new patcher executable")
+}
+
+#[cfg(not(unix))]
+async fn set_executable_permissions(_exe_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+
+    #[tokio::test]
+    async fn test_self_update() {
+        let new_exe_content = b"new patcher";
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/rpatchur.exe"))
+                .respond_with(status_code(200).body(&new_exe_content[..])),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/version.yml")).respond_with(
+                status_code(200).body(format!(
+                    "version: 99.0.0\nurl: {}\nsha256: {}\n",
+                    server.url("/rpatchur.exe"),
+                    hex::encode(Sha256::digest(new_exe_content))
+                )),
+            ),
+        );
+        let client = reqwest::Client::new();
+        let release = fetch_patcher_release(&client, &server.url("/version.yml").to_string())
+            .await
+            .unwrap();
+        assert!(release.is_newer());
+
+        let exe_dir = tempfile::tempdir().unwrap();
+        let exe_path = exe_dir.path().join("rpatchur.exe");
+        std::fs::write(&exe_path, b"old patcher").unwrap();
+        let rate_limiter = DownloadRateLimiter::new(None);
+        let new_exe_path =
+            download_patcher_release(&client, &release, &exe_path, &[], &rate_limiter)
+                .await
+                .unwrap();
+        replace_exe(&exe_path, &new_exe_path).unwrap();
+        assert_eq!(&new_exe_content[..], &std::fs::read(&exe_path).unwrap()[..]);
+        assert!(!new_exe_path.exists());
+
+        // Executables that don't match the hash are refused
+        let tampered_release = PatcherRelease {
+            sha256: hex::encode(Sha256::digest(b"other patcher")),
+            ..release
+        };
+        assert!(download_patcher_release(
+            &client,
+            &tampered_release,
+            &exe_path,
+            &[],
+            &rate_limiter
+        )
+        .await
+        .is_err());
+        assert!(!new_exe_path.exists());
+    }
+
+    #[test]
+    fn test_check_release_signature() {
+        use ed25519_dalek::{Keypair, SecretKey, Signer};
+
+        let keypair = |seed: u8| {
+            let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+            let public = PublicKey::from(&secret);
+            Keypair { secret, public }
+        };
+        let (trusted_keypair, other_keypair) = (keypair(1), keypair(2));
+        let trusted_public_keys = vec![hex::encode(trusted_keypair.public.as_bytes())];
+        let digest = Sha256::digest(b"new patcher");
+        let release = |signature: Option<String>| PatcherRelease {
+            version: "99.0.0".to_string(),
+            url: "https://myserver.com/rpatchur.exe".to_string(),
+            sha256: hex::encode(digest),
+            signature,
+        };
+
+        let signed_release = release(Some(hex::encode(
+            trusted_keypair.sign(&digest).to_bytes().as_ref(),
+        )));
+        assert!(check_release_signature(&signed_release, &digest, &trusted_public_keys).is_ok());
+        let other_digest = Sha256::digest(b"other patcher");
+        assert!(
+            check_release_signature(&signed_release, &other_digest, &trusted_public_keys).is_err()
+        );
+        let untrusted_release = release(Some(hex::encode(
+            other_keypair.sign(&digest).to_bytes().as_ref(),
+        )));
+        assert!(
+            check_release_signature(&untrusted_release, &digest, &trusted_public_keys).is_err()
+        );
+        assert!(check_release_signature(&release(None), &digest, &trusted_public_keys).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_relaunch_after_replace_exe() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let exe_dir = tempfile::tempdir().unwrap();
+        let exe_path = exe_dir.path().join("rpatchur");
+        let new_exe_path = append_extension(&exe_path, NEW_EXE_EXTENSION);
+        // Each version of the "patcher" writes its name in the working directory
+        for (path, version) in [(&exe_path, "old"), (&new_exe_path, "new")].iter() {
+            std::fs::write(path, format!("#!/bin/sh\necho {} > version\n", version)).unwrap();
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        replace_exe(&exe_path, &new_exe_path).unwrap();
+        let exit_status = relaunch(&exe_path, exe_dir.path(), vec![])
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(exit_status.success());
+        assert_eq!(
+            "new",
+            std::fs::read_to_string(exe_dir.path().join("version"))
+                .unwrap()
+                .trim()
+        );
+    }
+}
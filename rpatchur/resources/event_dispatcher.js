@@ -0,0 +1,40 @@
+// Forwards the events emitted by the patcher to the UI.
+//
+// Events are given to `rpatchur.onEvent` if the UI defines it. Otherwise, they
+// are translated into calls to the legacy `patchingStatus*` functions.
+(function (event) {
+    if (window.rpatchur && typeof window.rpatchur.onEvent === "function") {
+        window.rpatchur.onEvent(event);
+        return;
+    }
+    function call(functionName) {
+        var legacyFunction = window[functionName];
+        if (typeof legacyFunction === "function") {
+            legacyFunction.apply(null, Array.prototype.slice.call(arguments, 1));
+        }
+    }
+    switch (event.type) {
+        case "ready":
+            call("patchingStatusReady");
+            break;
+        case "error":
+            call("patchingStatusError", event.message);
+            break;
+        case "download_in_progress":
+            call("patchingStatusDownloading", event.downloaded_patches, event.total_patches,
+                event.bytes_per_sec);
+            break;
+        case "installation_in_progress":
+            call("patchingStatusInstalling", event.installed_patches, event.total_patches);
+            break;
+        case "manual_patch_applied":
+            call("patchingStatusPatchApplied", event.file_name);
+            break;
+        case "patch_servers_in_use":
+            call("patchingStatusServersInUse", event.names);
+            break;
+        case "patcher_update_in_progress":
+            call("patchingStatusUpdatingPatcher", event.version);
+            break;
+    }
+})
@@ -0,0 +1,305 @@
+//! Security policy of the bridge between the UI and the patcher.
+//!
+//! web-view doesn't tell which page sent a request. The first request received
+//! after the patcher loads a page is thus answered with a script, evaluated in
+//! the top-level document, that gives it a function tagging its requests with
+//! a session key. The script only does so if the document's origin is the
+//! opaque origin of local pages (the embedded and fallback UIs) or one of
+//! `window.allowed_origins` for the remote UI, depending on the page the
+//! patcher loaded, and the key is not handed out again until the patcher loads
+//! another page. Frames embedded in the page and pages the UI navigates to
+//! (e.g., pages it links to) don't get the key and cannot use the bridge.
+//!
+//! Since web-view doesn't tell which frame sent a request either, the request
+//! that triggers the hand-out is sent again by the top-level document, even if
+//! one of its frames sent it. Frames of the same origin as the page can also
+//! use its tagging function. UIs that embed third-party frames should thus use
+//! the bridge before loading them.
+//!
+//! Tagged requests also carry the origin reported by the page, which is
+//! checked again.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use url::Url;
+
+use crate::config::WindowConfiguration;
+
+/// Commands and functions that can be invoked by the UI
+const BRIDGE_COMMANDS: &[&str] = &[
+    "play",
+    "setup",
+    "exit",
+    "start_update",
+    "cancel_update",
+    "reset_cache",
+    "patch_history",
+    "manual_patch",
+    "login",
+    "launch",
+    "open_url",
+    "set_download_speed_limit",
+    "get_state",
+    "get_config_public",
+    "check_for_updates",
+    "get_patch_history",
+];
+
+/// URL schemes `open_url` accepts by default
+const DEFAULT_URL_SCHEMES: &[&str] = &["http", "https"];
+
+pub struct BridgePolicy {
+    message_prefix: String, // Prefix of the requests tagged with the session key and their origin
+    message_prefix_handed_out: bool, // Whether a page has been given the session key
+    remote_origins: Vec<String>, // Serialized origins of the remote UI (e.g., "https://myserver.com")
+    local_page: bool,            // Whether the page loaded by the patcher is local
+    allowed_commands: Option<Vec<String>>, // None if all commands are allowed
+    allowed_url_schemes: Vec<String>,
+}
+
+impl BridgePolicy {
+    /// Builds the policy described by the window's configuration.
+    ///
+    /// The policy expects a local page (the embedded UI) at first. For the
+    /// remote UI, only the origin of `index_url` is expected, unless
+    /// `allowed_origins` is set.
+    pub fn new(config: &WindowConfiguration, index_url: &str) -> Result<Self> {
+        let remote_origins = match &config.allowed_origins {
+            Some(origins) => origins
+                .iter()
+                .map(|origin| parse_origin(origin))
+                .collect::<Result<Vec<String>>>()?,
+            None => vec![parse_origin(index_url)?],
+        };
+        if let Some(commands) = &config.allowed_commands {
+            if let Some(command) = commands
+                .iter()
+                .find(|command| !BRIDGE_COMMANDS.contains(&command.as_str()))
+            {
+                return Err(anyhow!("Unknown command '{}' in allowed_commands", command));
+            }
+        }
+        let allowed_url_schemes = match &config.allowed_url_schemes {
+            Some(schemes) => schemes.iter().map(|s| s.to_ascii_lowercase()).collect(),
+            None => DEFAULT_URL_SCHEMES.iter().map(|s| s.to_string()).collect(),
+        };
+        Ok(Self {
+            message_prefix: generate_message_prefix()?,
+            message_prefix_handed_out: false,
+            remote_origins,
+            local_page: true,
+            allowed_commands: config.allowed_commands.clone(),
+            allowed_url_schemes,
+        })
+    }
+
+    /// Returns the prefix the page must add to a request (followed by its
+    /// origin and a new line) to have it handled.
+    ///
+    /// The prefix is only handed out once after a page has been loaded, and
+    /// None is returned afterwards. It must only be given to a document
+    /// whose origin is one of `expected_origins`.
+    pub fn hand_out_message_prefix(&mut self) -> Option<String> {
+        if self.message_prefix_handed_out {
+            return None;
+        }
+        self.message_prefix_handed_out = true;
+        Some(self.message_prefix.clone())
+    }
+
+    /// Splits a request tagged with its origin into the origin and the actual
+    /// request.
+    ///
+    /// Returns None if `message` hasn't been tagged.
+    pub fn untag_request<'a>(&self, message: &'a str) -> Option<(&'a str, &'a str)> {
+        let tagged_request = message.strip_prefix(self.message_prefix.as_str())?;
+        let mut parts = tagged_request.splitn(2, '\n');
+        Some((parts.next()?, parts.next()?))
+    }
+
+    /// Prepares the bridge for the page the patcher is about to load, which
+    /// will be the only page that can use it.
+    ///
+    /// A new session key is generated, so that the previous page cannot use
+    /// the bridge anymore.
+    pub fn expect_page(&mut self, local_page: bool) -> Result<()> {
+        self.message_prefix = generate_message_prefix()?;
+        self.message_prefix_handed_out = false;
+        self.local_page = local_page;
+        Ok(())
+    }
+
+    /// Returns the serialized origins the page loaded by the patcher can have.
+    ///
+    /// Local pages have an opaque origin, serialized as "null".
+    pub fn expected_origins(&self) -> Vec<String> {
+        if self.local_page {
+            vec!["null".to_string()]
+        } else {
+            self.remote_origins.clone()
+        }
+    }
+
+    /// Checks the origin reported by the page.
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.expected_origins()
+            .iter()
+            .any(|allowed| allowed == origin)
+    }
+
+    pub fn is_command_allowed(&self, command: &str) -> bool {
+        self.allowed_commands
+            .as_ref()
+            .map(|commands| commands.iter().any(|allowed| allowed == command))
+            .unwrap_or(true)
+    }
+
+    /// Checks that `url` can be given to `open_url`.
+    pub fn check_url(&self, url: &str) -> Result<Url> {
+        let url = Url::parse(url).with_context(|| format!("Invalid URL '{}'", url))?;
+        if !self
+            .allowed_url_schemes
+            .iter()
+            .any(|scheme| scheme == url.scheme())
+        {
+            return Err(anyhow!("URL scheme '{}' is not allowed", url.scheme()));
+        }
+        Ok(url)
+    }
+}
+
+/// Returns the name of the command invoked by `request`, which is either a
+/// command name or a JSON request.
+pub fn command_name(request: &str) -> Option<String> {
+    match serde_json::from_str::<Value>(request) {
+        Ok(json_req) => json_req["function"].as_str().map(|s| s.to_string()),
+        Err(_) => Some(request.to_string()),
+    }
+}
+
+/// Returns the serialized origin of `url`, as given by `window.location.origin`.
+fn parse_origin(url: &str) -> Result<String> {
+    let url = Url::parse(url).with_context(|| format!("Invalid origin '{}'", url))?;
+    Ok(url.origin().ascii_serialization())
+}
+
+/// Generates a prefix containing a key that cannot be guessed by the pages
+/// loaded by the patcher.
+fn generate_message_prefix() -> Result<String> {
+    let mut key = [0_u8; 32];
+    getrandom::getrandom(&mut key)
+        .map_err(|e| anyhow!("Failed to generate a session key: {}", e))?;
+    let key: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!("rpatchur:{}\n", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window_configuration() -> WindowConfiguration {
+        WindowConfiguration {
+            title: "RPatchur".to_string(),
+            width: 780,
+            height: 580,
+            resizable: false,
+            allowed_origins: None,
+            allowed_commands: None,
+            allowed_url_schemes: None,
+        }
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let mut config = window_configuration();
+        let mut policy = BridgePolicy::new(&config, "https://myserver.com/ui/index.html").unwrap();
+        assert!(policy.is_origin_allowed("null"));
+        assert!(!policy.is_origin_allowed("https://myserver.com"));
+        policy.expect_page(false).unwrap();
+        assert_eq!(vec!["https://myserver.com"], policy.expected_origins());
+        assert!(!policy.is_origin_allowed("null"));
+        assert!(policy.is_origin_allowed("https://myserver.com"));
+        assert!(!policy.is_origin_allowed("http://myserver.com"));
+        assert!(!policy.is_origin_allowed("https://evil.com"));
+
+        config.allowed_origins = Some(vec![
+            "https://myserver.com/".to_string(),
+            "http://localhost:8080".to_string(),
+        ]);
+        let mut policy = BridgePolicy::new(&config, "https://cdn.myserver.com/index.html").unwrap();
+        policy.expect_page(false).unwrap();
+        assert!(policy.is_origin_allowed("https://myserver.com"));
+        assert!(policy.is_origin_allowed("http://localhost:8080"));
+        assert!(!policy.is_origin_allowed("https://cdn.myserver.com"));
+
+        config.allowed_origins = Some(vec!["myserver.com".to_string()]);
+        assert!(BridgePolicy::new(&config, "https://myserver.com/").is_err());
+    }
+
+    #[test]
+    fn test_allowed_commands() {
+        let mut config = window_configuration();
+        let policy = BridgePolicy::new(&config, "https://myserver.com/").unwrap();
+        assert!(policy.is_command_allowed("manual_patch"));
+
+        config.allowed_commands = Some(vec!["play".to_string(), "open_url".to_string()]);
+        let policy = BridgePolicy::new(&config, "https://myserver.com/").unwrap();
+        assert!(policy.is_command_allowed("play"));
+        assert!(!policy.is_command_allowed("manual_patch"));
+        assert_eq!(
+            Some("open_url".to_string()),
+            command_name(r#"{"function": "open_url"}"#)
+        );
+        assert_eq!(Some("play".to_string()), command_name("play"));
+        assert_eq!(None, command_name(r#"{"parameters": {}}"#));
+
+        config.allowed_commands = Some(vec!["format_disk".to_string()]);
+        assert!(BridgePolicy::new(&config, "https://myserver.com/").is_err());
+    }
+
+    #[test]
+    fn test_check_url() {
+        let mut config = window_configuration();
+        let policy = BridgePolicy::new(&config, "https://myserver.com/").unwrap();
+        assert!(policy.check_url("https://myserver.com/forum").is_ok());
+        assert!(policy.check_url("HTTP://myserver.com/").is_ok());
+        assert!(policy
+            .check_url("file:///C:/Windows/System32/calc.exe")
+            .is_err());
+        assert!(policy.check_url("C:\\Windows\\System32\\calc.exe").is_err());
+        assert!(policy.check_url("calc.exe").is_err());
+
+        config.allowed_url_schemes = Some(vec!["Discord".to_string()]);
+        let policy = BridgePolicy::new(&config, "https://myserver.com/").unwrap();
+        assert!(policy.check_url("discord://invite/abc").is_ok());
+        assert!(policy.check_url("https://myserver.com/").is_err());
+    }
+
+    #[test]
+    fn test_untag_request() {
+        let mut policy =
+            BridgePolicy::new(&window_configuration(), "https://myserver.com/").unwrap();
+        let message_prefix = policy.hand_out_message_prefix().unwrap();
+        assert_eq!(None, policy.hand_out_message_prefix());
+        assert_eq!("rpatchur:".len() + 64 + 1, message_prefix.len());
+        // Pages loaded by the patcher get a new key
+        policy.expect_page(false).unwrap();
+        let new_message_prefix = policy.hand_out_message_prefix().unwrap();
+        assert_ne!(message_prefix, new_message_prefix);
+        assert_eq!(
+            None,
+            policy.untag_request(&format!("{}null\nplay", message_prefix))
+        );
+        let message_prefix = new_message_prefix;
+        let message = format!("{}https://myserver.com\nplay", message_prefix);
+        assert_eq!(
+            Some(("https://myserver.com", "play")),
+            policy.untag_request(&message)
+        );
+        assert_eq!(None, policy.untag_request("play"));
+        assert_eq!(
+            None,
+            policy.untag_request("rpatchur:0123456789abcdef\nhttps://myserver.com\nplay")
+        );
+    }
+}
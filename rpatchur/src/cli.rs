@@ -0,0 +1,325 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use structopt::StructOpt;
+use tokio::runtime;
+
+use crate::config::{retrieve_patcher_configuration, PatcherConfiguration};
+use futures::StreamExt;
+use rpatchur_core::{
+    get_patch_history, get_pending_patches, relaunch, reset_cache, Patcher, PatcherCommand,
+    PatcherEvent, PatcherHandle,
+};
+
+// Exit codes of the headless mode
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1; // A command failed
+pub const EXIT_INVALID_CONFIGURATION: i32 = 2; // The configuration couldn't be loaded
+pub const EXIT_UPDATE_AVAILABLE: i32 = 3; // 'status' found patches to apply
+
+/// Commands that can be run without UI (e.g., on servers or in CI jobs).
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Downloads and applies the available patches
+    Update,
+    /// Applies the given patch files (relative paths are resolved from the
+    /// directory the patcher is started from)
+    Apply {
+        #[structopt(required = true, parse(from_os_str))]
+        patches: Vec<PathBuf>,
+    },
+    /// Shows the applied patches and checks for new ones
+    Status,
+    /// Resets the patcher's cache (which is used to keep track of already
+    /// applied patches)
+    ResetCache,
+}
+
+/// Reports the patching process' status on the terminal.
+///
+/// Commands are sent to the patcher one at a time, each one being sent once
+/// the previous one has finished.
+struct TerminalController {
+    patcher: PatcherHandle,
+    pending_commands: Mutex<Vec<PatcherCommand>>, // Next command last
+    error_count: Arc<AtomicUsize>,
+}
+
+impl TerminalController {
+    /// Sends the next command to the patcher, or asks it to stop if there's
+    /// nothing left to do.
+    fn send_next_command(&self) {
+        let next_command = self
+            .pending_commands
+            .lock()
+            .ok()
+            .and_then(|mut pending_commands| pending_commands.pop())
+            .unwrap_or(PatcherCommand::Quit);
+        if !self.patcher.send(next_command) {
+            log::warn!("Failed to send command to patching thread");
+        }
+    }
+
+    /// Prints the events emitted by the patcher.
+    fn dispatch_event(&self, event: PatcherEvent) {
+        match event {
+            PatcherEvent::Ready => {
+                println!("Patching finished");
+                self.send_next_command();
+            }
+            PatcherEvent::Error(error) => {
+                eprintln!("Error: {}", error);
+                self.error_count.fetch_add(1, Ordering::SeqCst);
+                self.send_next_command();
+            }
+            PatcherEvent::DownloadInProgress(progress) => {
+                let eta = progress
+                    .eta_secs
+                    .map(|eta_secs| format!(", {}s left", eta_secs))
+                    .unwrap_or_default();
+                println!(
+                    "Downloading patches: {}/{} ({:.1} KiB/s{})",
+                    progress.downloaded_patches,
+                    progress.total_patches,
+                    progress.bytes_per_sec as f64 / 1024.0,
+                    eta
+                );
+            }
+            PatcherEvent::PatchDownloaded { file_name } => {
+                println!("Downloaded patch: {}", file_name);
+            }
+            PatcherEvent::InstallationInProgress {
+                installed_patches,
+                total_patches,
+            } => {
+                println!(
+                    "Installing patches: {}/{}",
+                    installed_patches, total_patches
+                );
+            }
+            PatcherEvent::PatchApplied { file_name } => {
+                println!("Applied patch: {}", file_name);
+            }
+            PatcherEvent::ManualPatchApplied { file_name } => {
+                println!("Successfully applied patch: {}", file_name);
+                self.send_next_command();
+            }
+            PatcherEvent::PatchListWarning(warning) => {
+                eprintln!("Warning: invalid patch list entry ({})", warning);
+            }
+            PatcherEvent::PatchServersInUse { names } => {
+                println!("Patch servers: {}", names.join(", "));
+            }
+            PatcherEvent::PatcherUpdateInProgress { version } => {
+                println!("Updating the patcher to version {}", version);
+            }
+            PatcherEvent::PatchingStarted
+            | PatcherEvent::PatchingStopped
+            | PatcherEvent::RelaunchRequired { .. } => {}
+        }
+    }
+}
+
+/// Runs `command` without UI and returns the exit code of the process.
+///
+/// Relative paths of the patches given to `apply` are resolved from
+/// `initial_working_directory`, the directory the patcher was started from, as
+/// is the configuration file's.
+pub fn run_command(
+    command: Command,
+    config_file_path: Option<PathBuf>,
+    initial_working_directory: &Path,
+) -> i32 {
+    #[cfg(windows)]
+    windows::attach_console();
+
+    let load_config = || match retrieve_patcher_configuration(config_file_path.clone()) {
+        Err(e) => {
+            eprintln!(
+                "Error: Failed to retrieve the patcher's configuration: {:#}",
+                e
+            );
+            Err(EXIT_INVALID_CONFIGURATION)
+        }
+        Ok(config) => Ok(config),
+    };
+    let result = match command {
+        Command::Update => load_config()
+            .map(|config| run_patcher_commands(config, vec![PatcherCommand::StartUpdate])),
+        Command::Apply { patches } => load_config().map(|config| {
+            let commands = patches
+                .into_iter()
+                .map(|path| PatcherCommand::ApplyPatch(initial_working_directory.join(path)))
+                .collect();
+            run_patcher_commands(config, commands)
+        }),
+        Command::Status => load_config().map(|config| show_status(&config)),
+        Command::ResetCache => load_config().map(|config| {
+            reset_cache(&config.engine()).map(|_| {
+                println!("Cache has been reset");
+                EXIT_SUCCESS.into()
+            })
+        }),
+    };
+    match result {
+        Err(exit_code) => exit_code,
+        Ok(Err(e)) => {
+            eprintln!("Error: {:#}", e);
+            EXIT_FAILURE
+        }
+        Ok(Ok(PatcherExit::Done(exit_code))) => exit_code,
+        Ok(Ok(PatcherExit::RelaunchRequired(exe_path))) => {
+            // Run the same command with the updated patcher
+            let arguments = std::env::args_os().skip(1).collect();
+            match relaunch(&exe_path, initial_working_directory, arguments).and_then(|mut child| {
+                child
+                    .wait()
+                    .with_context(|| "Failed to wait for the new patcher")
+            }) {
+                Ok(exit_status) => exit_status.code().unwrap_or(EXIT_FAILURE),
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    EXIT_FAILURE
+                }
+            }
+        }
+    }
+}
+
+/// Indicates how the patcher should exit once a command has been run.
+enum PatcherExit {
+    Done(i32),                 // Exit code
+    RelaunchRequired(PathBuf), // The patcher updated itself, path of the new patcher
+}
+
+impl From<i32> for PatcherExit {
+    fn from(exit_code: i32) -> Self {
+        PatcherExit::Done(exit_code)
+    }
+}
+
+/// Runs the patcher until all the given commands have been processed.
+fn run_patcher_commands(
+    config: PatcherConfiguration,
+    mut commands: Vec<PatcherCommand>,
+) -> Result<PatcherExit> {
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    let (patcher, patcher_handle, event_stream) = Patcher::new(config.engine());
+    let error_count = Arc::new(AtomicUsize::new(0));
+    commands.reverse();
+    let terminal_controller = TerminalController {
+        patcher: patcher_handle,
+        pending_commands: Mutex::new(commands),
+        error_count: error_count.clone(),
+    };
+    terminal_controller.send_next_command();
+
+    let print_events = event_stream.for_each(|event| {
+        terminal_controller.dispatch_event(event);
+        futures::future::ready(())
+    });
+    let (new_exe_path, _) =
+        tokio_rt.block_on(async { futures::join!(patcher.run(), print_events) });
+    if let Some(exe_path) = new_exe_path {
+        Ok(PatcherExit::RelaunchRequired(exe_path))
+    } else if error_count.load(Ordering::SeqCst) > 0 {
+        Ok(EXIT_FAILURE.into())
+    } else {
+        Ok(EXIT_SUCCESS.into())
+    }
+}
+
+/// Prints the applied patches as well as the patches that are available.
+fn show_status(config: &PatcherConfiguration) -> Result<PatcherExit> {
+    let patch_history = get_patch_history(&config.engine())?;
+    println!("Applied patches: {}", patch_history.len());
+    if let Some(last_patch) = patch_history.last() {
+        println!(
+            "Last applied patch: {} ({})",
+            last_patch.file_name, last_patch.index
+        );
+    }
+
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    let pending_patches = tokio_rt.block_on(get_pending_patches(&config.engine()))?;
+    if pending_patches.is_empty() {
+        println!("The game is up to date");
+        return Ok(EXIT_SUCCESS.into());
+    }
+    println!("Pending patches: {}", pending_patches.len());
+    for patch in pending_patches {
+        println!("  {} {}", patch.index, patch.file_name);
+    }
+    Ok(EXIT_UPDATE_AVAILABLE.into())
+}
+
+#[cfg(windows)]
+mod windows {
+    use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+    /// Attaches the process to the console it was started from, if any, since
+    /// the patcher is built for the "windows" subsystem.
+    pub fn attach_console() {
+        unsafe {
+            AttachConsole(ATTACH_PARENT_PROCESS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+    use rpatchur_core::PatchServerInfo;
+
+    #[test]
+    fn test_run_command_invalid_configuration() {
+        let directory = tempfile::tempdir().unwrap();
+        assert_eq!(
+            EXIT_INVALID_CONFIGURATION,
+            run_command(
+                Command::Status,
+                Some(directory.path().join("missing.yml")),
+                directory.path()
+            )
+        );
+    }
+
+    #[test]
+    fn test_show_status() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/plist.txt"))
+                .respond_with(status_code(200).body("1 patch1.thor\n")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/data/patch1.thor"))
+                .respond_with(status_code(200).body(vec![0_u8; 1024])),
+        );
+        let game_directory = tempfile::tempdir().unwrap();
+        let mut config: PatcherConfiguration =
+            serde_yaml::from_str(include_str!("../../examples/rpatchur.yml")).unwrap();
+        config.web.engine.patcher_update_url = None;
+        config.web.engine.patch_servers = vec![PatchServerInfo {
+            name: "server".to_string(),
+            plist_url: server.url("/plist.txt").to_string(),
+            patch_url: server.url("/data/").to_string(),
+            manifest_url: None,
+        }];
+        config.game_directory = game_directory.path().to_path_buf();
+        config.patcher_name = "rpatchur".into();
+
+        match show_status(&config) {
+            Ok(PatcherExit::Done(exit_code)) => assert_eq!(EXIT_UPDATE_AVAILABLE, exit_code),
+            _ => panic!("'status' should have found a patch to apply"),
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use rpatchur_core::{ClientConfiguration, EngineConfiguration, EnginePaths, PatchingConfiguration};
+use serde::Deserialize;
+use url::{Host, Url};
+
+use crate::template::{uses_placeholder, validate_template, LOGIN_PLACEHOLDERS, PLAY_PLACEHOLDERS};
+
+#[derive(Deserialize, Clone)]
+pub struct PatcherConfiguration {
+    pub window: WindowConfiguration,
+    pub play: PlayConfiguration,
+    pub setup: SetupConfiguration,
+    pub web: WebConfiguration,
+    pub client: ClientConfiguration,
+    pub patching: PatchingConfiguration,
+    pub login: Option<LoginConfiguration>,
+    #[serde(default)]
+    pub launch_profiles: Vec<LaunchProfileConfiguration>,
+    #[serde(skip)]
+    pub game_directory: PathBuf, // Working directory of the patcher when the configuration was loaded
+    #[serde(skip)]
+    pub patcher_name: OsString, // Name the patcher's cache, lock and download directory are named after
+}
+
+impl PatcherConfiguration {
+    /// Returns the settings used by the patching engine.
+    pub fn engine(&self) -> EngineConfiguration {
+        EngineConfiguration {
+            web: self.web.engine.clone(),
+            client: self.client.clone(),
+            patching: self.patching.clone(),
+            paths: EnginePaths::new(&self.game_directory, &self.patcher_name),
+        }
+    }
+
+    /// Returns the launch profile named `name`.
+    ///
+    /// The "play" and "setup" profiles are described by the `play` and `setup`
+    /// sections.
+    pub fn launch_profile(&self, name: &str) -> Option<LaunchProfileConfiguration> {
+        match name {
+            PLAY_PROFILE_NAME => Some(LaunchProfileConfiguration {
+                name: name.to_string(),
+                path: self.play.path.clone(),
+                arguments: self.play.arguments.clone(),
+                working_directory: None,
+                exit_on_success: Some(self.play.exit_on_success.unwrap_or(true)),
+                environment: BTreeMap::new(),
+                runner: self.play.runner.clone(),
+            }),
+            SETUP_PROFILE_NAME => Some(LaunchProfileConfiguration {
+                name: name.to_string(),
+                path: self.setup.path.clone(),
+                arguments: self.setup.arguments.clone(),
+                working_directory: None,
+                exit_on_success: Some(self.setup.exit_on_success.unwrap_or(false)),
+                environment: BTreeMap::new(),
+                runner: self.setup.runner.clone(),
+            }),
+            _ => self
+                .launch_profiles
+                .iter()
+                .find(|profile| profile.name == name)
+                .cloned(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct WebConfiguration {
+    pub index_url: String,          // URL of the index file implementing the UI
+    pub index_timeout: Option<u64>, // Time (in seconds) after which the UI is considered unavailable
+    pub fallback_index_path: Option<String>, // Local HTML file used as the UI when index_url is unavailable
+    #[serde(flatten)]
+    pub engine: rpatchur_core::WebConfiguration, // Patch servers and settings used by the patching engine
+}
+
+#[derive(Deserialize, Clone)]
+pub struct WindowConfiguration {
+    pub title: String,
+    pub width: i32,
+    pub height: i32,
+    pub resizable: bool,
+    pub allowed_origins: Option<Vec<String>>, // Origins the page may report when using the bridge, defaults to index_url's
+    pub allowed_commands: Option<Vec<String>>, // Commands pages are allowed to invoke, defaults to all of them
+    pub allowed_url_schemes: Option<Vec<String>>, // URL schemes open_url accepts, defaults to http and https
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PlayConfiguration {
+    pub path: String,
+    pub arguments: Vec<String>, // Template of the arguments given to the client
+    pub login_arguments: Option<Vec<String>>, // Template of the arguments given to the client by the login function
+    pub server: Option<String>,               // Value of the {server} placeholder
+    pub lang: Option<String>,                 // Value of the {lang} placeholder
+    pub exit_on_success: Option<bool>,
+    pub runner: Option<RunnerConfiguration>, // Program used to start the client on non-Windows systems
+}
+
+impl PlayConfiguration {
+    /// Returns the template of the arguments given to the client by the login
+    /// function.
+    ///
+    /// Defaults to the token (or password) and login followed by the
+    /// arguments used to play.
+    pub fn login_arguments(&self) -> Vec<String> {
+        match &self.login_arguments {
+            Some(login_arguments) => login_arguments.clone(),
+            None => {
+                let mut login_arguments = vec![
+                    "-t:{token}".to_string(),
+                    "{login}".to_string(),
+                    "server".to_string(),
+                ];
+                login_arguments.extend(self.arguments.iter().cloned());
+                login_arguments
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SetupConfiguration {
+    pub path: String,
+    pub arguments: Vec<String>,
+    pub exit_on_success: Option<bool>,
+    pub runner: Option<RunnerConfiguration>, // Program used to start the setup software on non-Windows systems
+}
+
+pub const PLAY_PROFILE_NAME: &str = "play";
+pub const SETUP_PROFILE_NAME: &str = "setup";
+
+/// Executable the UI can start by name.
+#[derive(Deserialize, Clone)]
+pub struct LaunchProfileConfiguration {
+    pub name: String,                      // Name used by the UI to start the executable
+    pub path: String,                      // Path of the executable
+    pub arguments: Vec<String>,            // Template of the arguments given to the executable
+    pub working_directory: Option<String>, // Directory the executable is started in, defaults to the patcher's
+    pub exit_on_success: Option<bool>,     // Exit the patcher once the executable has started
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>, // Environment variables set for the executable
+    pub runner: Option<RunnerConfiguration>, // Program used to start the executable on non-Windows systems
+}
+
+/// Program that starts Windows executables on other systems.
+#[derive(Deserialize, Clone)]
+pub struct RunnerConfiguration {
+    pub kind: RunnerKind,
+    pub command: Option<String>, // Runner's executable, defaults to "wine" for Wine
+    #[serde(default)]
+    pub arguments: Vec<String>, // Arguments given to the runner, before the executable's path
+    pub prefix: Option<String>,  // Wine prefix (compatibility data directory for Proton)
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>, // Environment variables set for the runner
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunnerKind {
+    Wine,   // Runs `<command> <executable>` with WINEPREFIX set to the prefix
+    Proton, // Runs `<command> run <executable>` with STEAM_COMPAT_DATA_PATH set to the prefix
+    Custom, // Runs `<command> <executable>` (with WINEPREFIX set if a prefix is given)
+}
+
+/// Token-based login: credentials are exchanged for a one-time token, which is
+/// given to the game client instead of the password.
+#[derive(Deserialize, Clone)]
+pub struct LoginConfiguration {
+    pub url: String,          // URL of the login endpoint credentials are POSTed to
+    pub timeout: Option<u64>, // Time (in seconds) after which the login endpoint is considered unavailable
+}
+
+pub fn retrieve_patcher_configuration(
+    config_file_path: Option<PathBuf>,
+) -> Result<PatcherConfiguration> {
+    let patcher_name = get_patcher_name()?;
+    // Use given configuration path if present
+    let config_file_path =
+        config_file_path.unwrap_or_else(|| PathBuf::from(&patcher_name).with_extension("yml"));
+    // Read the YAML content of the file as an instance of `PatcherConfiguration`.
+    let mut config = parse_configuration(config_file_path)?;
+    validate_configuration(&config)?;
+    // The game client is in the working directory
+    config.game_directory =
+        env::current_dir().context("Failed to resolve current working directory")?;
+    config.patcher_name = patcher_name;
+    Ok(config)
+}
+
+/// Returns the name of the patcher's executable, without its extension.
+fn get_patcher_name() -> Result<OsString> {
+    let current_exe_path = env::current_exe()?;
+    Ok(current_exe_path
+        .file_stem()
+        .context("Current executable path is invalid")?
+        .to_os_string())
+}
+
+fn parse_configuration(config_file_path: impl AsRef<Path>) -> Result<PatcherConfiguration> {
+    let config_file = File::open(config_file_path)?;
+    let config_reader = BufReader::new(config_file);
+    serde_yaml::from_reader(config_reader).context("Invalid configuration")
+}
+
+/// Checks that the argument templates are valid, that the launch profiles
+/// can be told apart and that credentials are sent over HTTPS.
+fn validate_configuration(config: &PatcherConfiguration) -> Result<()> {
+    let play_config = &config.play;
+    validate_arguments(&play_config.arguments, PLAY_PLACEHOLDERS, play_config)
+        .context("Invalid play.arguments")?;
+    validate_arguments(
+        &play_config.login_arguments(),
+        LOGIN_PLACEHOLDERS,
+        play_config,
+    )
+    .context("Invalid play.login_arguments")?;
+    validate_arguments(&config.setup.arguments, PLAY_PLACEHOLDERS, play_config)
+        .context("Invalid setup.arguments")?;
+    validate_runner(play_config.runner.as_ref()).context("Invalid play.runner")?;
+    validate_runner(config.setup.runner.as_ref()).context("Invalid setup.runner")?;
+    if let Some(login_config) = &config.login {
+        validate_login_url(&login_config.url).context("Invalid login.url")?;
+    }
+    let mut profile_names = HashSet::new();
+    for profile in &config.launch_profiles {
+        let name = profile.name.as_str();
+        if name == PLAY_PROFILE_NAME || name == SETUP_PROFILE_NAME {
+            return Err(anyhow!("Launch profile name '{}' is reserved", name));
+        }
+        if !profile_names.insert(name) {
+            return Err(anyhow!("Launch profile '{}' is defined twice", name));
+        }
+        validate_arguments(&profile.arguments, PLAY_PLACEHOLDERS, play_config)
+            .with_context(|| format!("Invalid arguments for launch profile '{}'", name))?;
+        validate_runner(profile.runner.as_ref())
+            .with_context(|| format!("Invalid runner for launch profile '{}'", name))?;
+    }
+    Ok(())
+}
+
+/// Checks that `template` is valid and that the values of the placeholders it
+/// uses are configured.
+fn validate_arguments(
+    template: &[String],
+    placeholders: &[&str],
+    play_config: &PlayConfiguration,
+) -> Result<()> {
+    validate_template(template, placeholders)?;
+    if play_config.server.is_none() && uses_placeholder(template, "server") {
+        return Err(anyhow!("{{server}} is used but play.server is not set"));
+    }
+    if play_config.lang.is_none() && uses_placeholder(template, "lang") {
+        return Err(anyhow!("{{lang}} is used but play.lang is not set"));
+    }
+    Ok(())
+}
+
+/// Checks that credentials POSTed to `url` are encrypted, which is the case
+/// with HTTPS or when they don't leave the machine.
+fn validate_login_url(url: &str) -> Result<()> {
+    let url = Url::parse(url).with_context(|| format!("Invalid URL '{}'", url))?;
+    let is_loopback = match url.host() {
+        Some(Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+        Some(Host::Ipv4(address)) => address.is_loopback(),
+        Some(Host::Ipv6(address)) => address.is_loopback(),
+        None => false,
+    };
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if is_loopback => Ok(()),
+        scheme => Err(anyhow!(
+            "'{}' URLs are only allowed for loopback addresses, use 'https'",
+            scheme
+        )),
+    }
+}
+
+/// Checks that the settings required by the runner's kind are set.
+fn validate_runner(runner: Option<&RunnerConfiguration>) -> Result<()> {
+    let runner = match runner {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    if runner.kind != RunnerKind::Wine && runner.command.is_none() {
+        return Err(anyhow!("command is required by {:?} runners", runner.kind));
+    }
+    if runner.kind == RunnerKind::Proton {
+        if runner.prefix.is_none() {
+            return Err(anyhow!("prefix is required by Proton runners"));
+        }
+        // Proton refuses to start without it
+        if !runner
+            .environment
+            .contains_key("STEAM_COMPAT_CLIENT_INSTALL_PATH")
+        {
+            return Err(anyhow!(
+                "environment.STEAM_COMPAT_CLIENT_INSTALL_PATH is required by Proton runners"
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_configuration() -> PatcherConfiguration {
+        serde_yaml::from_str(include_str!("../../examples/rpatchur.yml")).unwrap()
+    }
+
+    #[test]
+    fn test_validate_configuration() {
+        let mut config = example_configuration();
+        config.play.arguments = vec!["1sak1".to_string(), "/lang:{lang}".to_string()];
+        config.play.login_arguments = None;
+        config.play.server = None;
+        assert!(validate_configuration(&config).is_ok());
+        assert_eq!(
+            vec!["-t:{token}", "{login}", "server", "1sak1", "/lang:{lang}"],
+            config.play.login_arguments()
+        );
+
+        config.play.arguments = vec!["{login}".to_string()];
+        assert!(validate_configuration(&config).is_err());
+
+        config.play.arguments = vec![];
+        config.play.login_arguments = Some(vec!["{login}".to_string(), "{server}".to_string()]);
+        assert!(validate_configuration(&config).is_err());
+        config.play.server = Some("server".to_string());
+        assert!(validate_configuration(&config).is_ok());
+    }
+
+    #[test]
+    fn test_launch_profiles() {
+        let mut config = example_configuration();
+        assert!(validate_configuration(&config).is_ok());
+        let play_profile = config.launch_profile(PLAY_PROFILE_NAME).unwrap();
+        assert_eq!(config.play.path, play_profile.path);
+        assert_eq!(Some(false), play_profile.exit_on_success);
+        let setup_profile = config.launch_profile(SETUP_PROFILE_NAME).unwrap();
+        assert_eq!(config.setup.path, setup_profile.path);
+        let profile = config.launch_profiles[0].clone();
+        assert_eq!(
+            Some(profile.path.as_str()),
+            config
+                .launch_profile(&profile.name)
+                .as_ref()
+                .map(|p| p.path.as_str())
+        );
+        assert!(config.launch_profile("missing").is_none());
+
+        config.launch_profiles.push(profile);
+        assert!(validate_configuration(&config).is_err());
+        config.launch_profiles.pop();
+        config.launch_profiles[0].name = PLAY_PROFILE_NAME.to_string();
+        assert!(validate_configuration(&config).is_err());
+    }
+
+    #[test]
+    fn test_validate_runner() {
+        let mut config = example_configuration();
+        let mut runner = config.play.runner.clone().unwrap();
+        assert_eq!(RunnerKind::Wine, runner.kind);
+        runner.command = None;
+        assert!(validate_runner(Some(&runner)).is_ok());
+
+        runner.kind = RunnerKind::Proton;
+        assert!(validate_runner(Some(&runner)).is_err());
+        runner.command = Some("/opt/proton/proton".to_string());
+        runner.prefix = None;
+        assert!(validate_runner(Some(&runner)).is_err());
+        runner.prefix = Some("compatdata".to_string());
+        assert!(validate_runner(Some(&runner)).is_err());
+        runner.environment.insert(
+            "STEAM_COMPAT_CLIENT_INSTALL_PATH".to_string(),
+            "/home/user/.steam/steam".to_string(),
+        );
+        assert!(validate_runner(Some(&runner)).is_ok());
+
+        runner.kind = RunnerKind::Custom;
+        runner.command = None;
+        config.setup.runner = Some(runner);
+        assert!(validate_configuration(&config).is_err());
+    }
+
+    #[test]
+    fn test_validate_login_url() {
+        let mut config = example_configuration();
+        assert!(validate_configuration(&config).is_ok());
+        assert!(validate_login_url("http://localhost:8080/login").is_ok());
+        assert!(validate_login_url("http://127.0.0.1/login").is_ok());
+        assert!(validate_login_url("http://[::1]/login").is_ok());
+        assert!(validate_login_url("http://myserver.com/login").is_err());
+        assert!(validate_login_url("ftp://127.0.0.1/login").is_err());
+        assert!(validate_login_url("myserver.com/login").is_err());
+
+        config.login = Some(LoginConfiguration {
+            url: "http://myserver.com/api/login".to_string(),
+            timeout: None,
+        });
+        assert!(validate_configuration(&config).is_err());
+    }
+}
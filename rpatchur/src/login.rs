@@ -0,0 +1,191 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::LoginConfiguration;
+
+const DEFAULT_LOGIN_TIMEOUT_SECS: u64 = 10;
+
+/// Events sent to the UI during a token-based login.
+///
+/// Like the patcher's events, they're given to `rpatchur.onEvent`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum LoginEvent {
+    /// Credentials have been sent to the login endpoint
+    #[serde(rename = "login_started")]
+    Started,
+    /// A token has been received and the game client is being started
+    #[serde(rename = "login_succeeded")]
+    Succeeded,
+    #[serde(rename = "login_failed")]
+    Failed(LoginError),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoginError {
+    pub code: LoginErrorCode,
+    pub message: String, // Human-readable description of the error
+}
+
+impl LoginError {
+    fn new(code: LoginErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Machine-readable kind of a `LoginError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginErrorCode {
+    InvalidCredentials, // The login endpoint refused the credentials
+    ServerUnavailable,  // The login endpoint couldn't be reached or failed
+    InvalidResponse,    // The login endpoint's response doesn't contain a token
+}
+
+/// Credentials sent to the login endpoint
+#[derive(Serialize)]
+struct LoginRequest<'a> {
+    login: &'a str,
+    password: &'a str,
+}
+
+/// Response of the login endpoint
+#[derive(Deserialize)]
+struct LoginResponse {
+    token: Option<String>, // One-time token, on success
+    error: Option<String>, // Reason of the failure, on failure
+}
+
+/// Exchanges the user's credentials for a one-time token.
+///
+/// Credentials are POSTed as a JSON object (`{"login": ..., "password": ...}`)
+/// to the login endpoint, which answers with `{"token": ...}` or, on failure,
+/// with an error status and an optional `{"error": ...}` object.
+pub async fn request_login_token(
+    config: &LoginConfiguration,
+    login: &str,
+    password: &str,
+) -> Result<String, LoginError> {
+    let timeout = Duration::from_secs(config.timeout.unwrap_or(DEFAULT_LOGIN_TIMEOUT_SECS));
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| LoginError::new(LoginErrorCode::ServerUnavailable, e.to_string()))?;
+    let response = client
+        .post(&config.url)
+        .json(&LoginRequest { login, password })
+        .send()
+        .await
+        .map_err(|e| {
+            LoginError::new(
+                LoginErrorCode::ServerUnavailable,
+                format!("Failed to reach the login server: {}", e),
+            )
+        })?;
+    let status = response.status();
+    // Error responses may not contain JSON
+    let body: Option<LoginResponse> = response.json().await.ok();
+    if status.is_success() {
+        return body.and_then(|body| body.token).ok_or_else(|| {
+            LoginError::new(
+                LoginErrorCode::InvalidResponse,
+                "The login server didn't send a token",
+            )
+        });
+    }
+    let code = if status.is_client_error() {
+        LoginErrorCode::InvalidCredentials
+    } else {
+        LoginErrorCode::ServerUnavailable
+    };
+    let message = body
+        .and_then(|body| body.error)
+        .unwrap_or_else(|| format!("The login server returned {}", status));
+    Err(LoginError::new(code, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{all_of, matchers::*, responders::*, Expectation, Server};
+
+    fn login_configuration(url: String) -> LoginConfiguration {
+        LoginConfiguration {
+            url,
+            timeout: Some(1),
+        }
+    }
+
+    fn request_token(server: &Server, path: &str) -> Result<String, LoginError> {
+        let tokio_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let config = login_configuration(server.url_str(path));
+        tokio_rt.block_on(request_login_token(&config, "user", "pass"))
+    }
+
+    #[test]
+    fn test_request_login_token() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/login"),
+                request::headers(contains(("content-type", "application/json"))),
+            ])
+            .respond_with(status_code(200).body(r#"{"token": "abcdef"}"#)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/refused"))
+                .respond_with(status_code(401).body(r#"{"error": "Invalid password"}"#)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/failing"))
+                .respond_with(status_code(503)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/empty"))
+                .respond_with(status_code(200).body("{}")),
+        );
+
+        assert_eq!(Ok("abcdef".to_string()), request_token(&server, "/login"));
+        assert_eq!(
+            Err(LoginError::new(
+                LoginErrorCode::InvalidCredentials,
+                "Invalid password"
+            )),
+            request_token(&server, "/refused")
+        );
+        assert_eq!(
+            LoginErrorCode::ServerUnavailable,
+            request_token(&server, "/failing").unwrap_err().code
+        );
+        assert_eq!(
+            LoginErrorCode::InvalidResponse,
+            request_token(&server, "/empty").unwrap_err().code
+        );
+    }
+
+    #[test]
+    fn test_login_event_serialization() {
+        assert_eq!(
+            r#"{"type":"login_failed","code":"invalid_credentials","message":"Failed"}"#,
+            serde_json::to_string(&LoginEvent::Failed(LoginError::new(
+                LoginErrorCode::InvalidCredentials,
+                "Failed"
+            )))
+            .unwrap()
+        );
+    }
+}
@@ -1,7 +1,11 @@
 #![windows_subsystem = "windows"]
 
-mod patcher;
+mod bridge;
+mod cli;
+mod config;
+mod login;
 mod process;
+mod template;
 mod ui;
 
 use log::LevelFilter;
@@ -9,15 +13,16 @@ use std::env;
 use std::path::PathBuf;
 
 use anyhow::{anyhow, Context, Result};
+use futures::StreamExt;
+use rpatchur_core::{relaunch, remove_previous_exe, Patcher, PatcherEventStream};
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
 use tinyfiledialogs as tfd;
 use tokio::runtime;
 
-use patcher::{
-    patcher_thread_routine, retrieve_patcher_configuration, PatcherCommand, PatcherConfiguration,
-};
-use ui::{UiController, WebViewUserData};
+use bridge::BridgePolicy;
+use config::retrieve_patcher_configuration;
+use ui::{WebViewController, WebViewUserData};
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -30,23 +35,49 @@ struct Opt {
     /// Sets a custom working directory
     #[structopt(short, long, parse(from_os_str))]
     working_directory: Option<PathBuf>,
+    /// Sets a custom configuration file (relative paths are resolved from the
+    /// directory the patcher is started from, like the patches given to apply)
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Runs a command without showing the UI
+    #[structopt(subcommand)]
+    command: Option<cli::Command>,
 }
 
 fn main() -> Result<()> {
     SimpleLogger::new()
         .with_level(LevelFilter::Off)
         .with_module_level(PKG_NAME, LevelFilter::Info)
+        .with_module_level("rpatchur_core", LevelFilter::Info)
         .init()
         .with_context(|| "Failed to initalize the logger")?;
 
+    // Remove the executable replaced by the last self-update
+    remove_previous_exe();
+
     // Parse CLI arguments
+    let initial_working_directory = env::current_dir()?;
     let cli_args = Opt::from_args();
+    // Paths given on the command line aren't affected by --working-directory
+    let config_file_path = cli_args
+        .config
+        .map(|path| initial_working_directory.join(path));
     if let Some(working_directory) = cli_args.working_directory {
         env::set_current_dir(working_directory)
             .with_context(|| "Specified working directory is invalid or inaccessible")?;
     };
+    // Headless mode
+    if let Some(command) = cli_args.command {
+        let exit_code = cli::run_command(command, config_file_path, &initial_working_directory);
+        std::process::exit(exit_code);
+    }
 
-    let config = match retrieve_patcher_configuration(None) {
+    let configuration = retrieve_patcher_configuration(config_file_path).and_then(|config| {
+        let bridge_policy = BridgePolicy::new(&config.window, &config.web.index_url)
+            .with_context(|| "Invalid bridge policy")?;
+        Ok((config, bridge_policy))
+    });
+    let (config, bridge_policy) = match configuration {
         Err(e) => {
             let err_msg = "Failed to retrieve the patcher's configuration";
             tfd::message_box_ok(
@@ -59,35 +90,46 @@ fn main() -> Result<()> {
         Ok(v) => v,
     };
 
-    // Create a channel to allow the webview's thread to communicate with the patching thread
-    let (tx, rx) = flume::bounded(32);
+    // The webview's thread controls the patcher through its handle
+    let (patcher, patcher_handle, event_stream) = Patcher::new(config.engine());
     let window_title = config.window.title.clone();
     let webview = ui::build_webview(
         window_title.as_str(),
-        WebViewUserData::new(config.clone(), tx),
+        WebViewUserData::new(config, bridge_policy, patcher_handle),
     )
     .with_context(|| "Failed to build a web view")?;
+    ui::load_ui(&webview);
 
     // Spawn a patching thread
-    let patching_thread = new_patching_thread(rx, UiController::new(&webview), config);
+    let patching_thread =
+        new_patching_thread(patcher, event_stream, WebViewController::new(&webview));
     webview
         .run()
         .with_context(|| "Failed to run the web view")?;
     // Join the patching thread
-    patching_thread
+    let new_exe_path = patching_thread
         .join()
         .map_err(|_| anyhow!("Failed to join patching thread"))?
         .with_context(|| "Patching thread ran into an error")?;
+    // Start the new patcher with the same arguments if the patcher updated itself
+    if let Some(exe_path) = new_exe_path {
+        relaunch(
+            &exe_path,
+            &initial_working_directory,
+            env::args_os().skip(1).collect(),
+        )?;
+    }
 
     Ok(())
 }
 
-/// Spawns a new thread that runs a single threaded tokio runtime to execute the patcher routine
+/// Spawns a new thread that runs a single threaded tokio runtime to execute the
+/// patcher and forward its events to the UI
 fn new_patching_thread(
-    rx: flume::Receiver<PatcherCommand>,
-    ui_ctrl: UiController,
-    config: PatcherConfiguration,
-) -> std::thread::JoinHandle<Result<()>> {
+    patcher: Patcher,
+    event_stream: PatcherEventStream,
+    ui_ctrl: WebViewController,
+) -> std::thread::JoinHandle<Result<Option<PathBuf>>> {
     std::thread::spawn(move || {
         // Build a tokio runtime that runs a scheduler on the current thread and a reactor
         let tokio_rt = runtime::Builder::new_current_thread()
@@ -95,8 +137,12 @@ fn new_patching_thread(
             .build()
             .with_context(|| "Failed to build a tokio runtime")?;
         // Block on the patching task from our synchronous function
-        tokio_rt.block_on(patcher_thread_routine(ui_ctrl, config, rx));
-
-        Ok(())
+        let forward_events = event_stream.for_each(|event| {
+            ui_ctrl.dispatch_event(event);
+            futures::future::ready(())
+        });
+        let (new_exe_path, _) =
+            tokio_rt.block_on(async { futures::join!(patcher.run(), forward_events) });
+        Ok(new_exe_path)
     })
 }
@@ -1,40 +1,250 @@
-use anyhow::Result;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+use std::process::Child;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::config::RunnerConfiguration;
+
+/// Events sent to the UI once a launch profile has been started (or not).
+///
+/// Like the patcher's events, they're given to `rpatchur.onEvent`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum LaunchEvent {
+    /// The executable is running (or has exited successfully)
+    #[serde(rename = "launch_succeeded")]
+    Succeeded { profile: String },
+    /// The executable couldn't be started or exited with an error right away
+    #[serde(rename = "launch_failed")]
+    Failed { profile: String, message: String },
+}
+
+/// Options used to start an executable
+pub struct StartOptions<'a> {
+    pub working_directory: Option<&'a str>, // Relative to the patcher's working directory
+    pub environment: Option<&'a BTreeMap<String, String>>, // Variables added to the environment inherited from the patcher
+    pub runner: Option<&'a RunnerConfiguration>, // Used to start the executable on non-Windows systems
+}
+
+/// Process started by `start_executable`.
+pub struct StartedProcess {
+    child: Option<Child>, // None if the process cannot be watched
+}
+
+impl StartedProcess {
+    /// Watches the process for `delay` and returns an error if it exits with
+    /// a failure in the meantime.
+    ///
+    /// Runners and game clients can fail right after being spawned (e.g., when
+    /// the Wine prefix is invalid or a DLL is missing). Processes that are
+    /// still running are reaped by another thread once they exit.
+    pub fn wait_for_startup(self, delay: Duration) -> Result<()> {
+        let mut child = match self.child {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            if let Some(exit_status) = child.try_wait()? {
+                if exit_status.success() {
+                    return Ok(());
+                }
+                return Err(anyhow!("The process exited with {}", exit_status));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        thread::spawn(move || {
+            if let Err(e) = child.wait() {
+                log::warn!("Failed to wait for the process: {}.", e);
+            }
+        });
+        Ok(())
+    }
+}
 
 /// Starts an executable file in a cross-platform way.
 ///
+/// Returns None if the process hasn't been started (e.g., because the user
+/// refused to elevate it).
+///
 /// This is the Windows version.
 #[cfg(windows)]
-pub fn start_executable<I, S>(exe_path: &str, exe_arguments: I) -> Result<bool>
+pub fn start_executable<I, S>(
+    exe_path: &str,
+    exe_arguments: I,
+    options: StartOptions,
+) -> Result<Option<StartedProcess>>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
 {
+    use std::ffi::OsStr;
+    use std::process::Command;
+
+    // ERROR_ELEVATION_REQUIRED
+    const ELEVATION_REQUIRED: i32 = 740;
+
+    if options.runner.is_some() {
+        log::debug!("Runners are ignored on Windows");
+    }
+    let working_directory = options.working_directory.map(absolute_path).transpose()?;
+    if let Some(environment) = options
+        .environment
+        .filter(|environment| !environment.is_empty())
+    {
+        // ShellExecuteEx cannot be given an environment block, CreateProcess
+        // can but doesn't elevate processes
+        let mut command = Command::new(absolute_path(exe_path)?);
+        if let Some(working_directory) = working_directory {
+            command.current_dir(working_directory);
+        }
+        return match command
+            .args(exe_arguments.into_iter().map(|e| e.as_ref().to_string()))
+            .envs(environment)
+            .spawn()
+        {
+            Ok(child) => Ok(Some(StartedProcess { child: Some(child) })),
+            Err(e) if e.raw_os_error() == Some(ELEVATION_REQUIRED) => Err(anyhow!(
+                "'{}' must be run as administrator, which is not possible when \
+                 environment variables are set",
+                exe_path
+            )),
+            Err(e) => Err(e.into()),
+        };
+    }
     // Fold parameter list into a String
     let exe_parameter = exe_arguments
         .into_iter()
         .fold(String::new(), |a: String, b| a + " " + b.as_ref() + "");
-    windows::win32_spawn_process_runas(exe_path, &exe_parameter)
+    let result = windows::win32_spawn_process_runas(
+        OsStr::new(exe_path),
+        OsStr::new(&exe_parameter),
+        working_directory.as_ref().map(|p| p.as_os_str()),
+    );
+    // Processes started with ShellExecuteEx cannot be watched
+    if result? {
+        Ok(Some(StartedProcess { child: None }))
+    } else {
+        Ok(None)
+    }
 }
 
 /// Starts an executable file in a cross-platform way.
 ///
+/// Returns None if the process hasn't been started (e.g., because the user
+/// refused to elevate it).
+///
 /// This is the non-Windows version.
 #[cfg(not(windows))]
-pub fn start_executable<I, S>(exe_path: &str, exe_arguments: I) -> Result<bool>
+pub fn start_executable<I, S>(
+    exe_path: &str,
+    exe_arguments: I,
+    options: StartOptions,
+) -> Result<Option<StartedProcess>>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
 {
+    use std::io;
     use std::process::Command;
 
     let exe_arguments: Vec<String> = exe_arguments
         .into_iter()
         .map(|e| e.as_ref().into())
         .collect();
-    Command::new(exe_path)
-        .args(exe_arguments)
-        .spawn()
-        .map(|_| Ok(true))?
+    let mut command = match options.runner {
+        None => match options.working_directory {
+            None => Command::new(exe_path),
+            // Relative paths would be resolved from the new working directory
+            Some(_) => Command::new(absolute_path(exe_path)?),
+        },
+        Some(runner) => {
+            let runner_command = RunnerCommand::new(runner);
+            let mut command = Command::new(&runner_command.program);
+            command
+                .args(runner_command.arguments)
+                .arg(absolute_path(exe_path)?)
+                .envs(runner_command.environment);
+            command
+        }
+    };
+    if let Some(working_directory) = options.working_directory {
+        command.current_dir(absolute_path(working_directory)?);
+    }
+    if let Some(environment) = options.environment {
+        command.envs(environment);
+    }
+    match command.args(exe_arguments).spawn() {
+        Ok(child) => Ok(Some(StartedProcess { child: Some(child) })),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let program = options
+                .runner
+                .map(|runner| RunnerCommand::new(runner).program)
+                .unwrap_or_else(|| exe_path.to_string());
+            Err(anyhow!("'{}' cannot be found", program))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Command that starts Windows executables through a runner
+#[cfg(not(windows))]
+struct RunnerCommand {
+    program: String,
+    arguments: Vec<String>, // Given before the executable's path
+    environment: Vec<(String, String)>,
+}
+
+#[cfg(not(windows))]
+impl RunnerCommand {
+    fn new(runner: &RunnerConfiguration) -> Self {
+        use crate::config::RunnerKind;
+
+        let program = runner.command.clone().unwrap_or_else(|| "wine".to_string());
+        let mut arguments = vec![];
+        let mut environment = vec![];
+        // Prefixes are resolved relatively to the patcher's working directory
+        let prefix = runner.prefix.as_ref().map(|prefix| {
+            let prefix = env::current_dir().unwrap_or_default().join(prefix);
+            prefix.to_string_lossy().into_owned()
+        });
+        match runner.kind {
+            RunnerKind::Wine | RunnerKind::Custom => {
+                if let Some(prefix) = prefix {
+                    environment.push(("WINEPREFIX".to_string(), prefix));
+                }
+            }
+            RunnerKind::Proton => {
+                arguments.push("run".to_string());
+                if let Some(prefix) = prefix {
+                    environment.push(("STEAM_COMPAT_DATA_PATH".to_string(), prefix));
+                }
+            }
+        }
+        arguments.extend(runner.arguments.iter().cloned());
+        // Variables set in the configuration take precedence
+        environment.extend(
+            runner
+                .environment
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        Self {
+            program,
+            arguments,
+            environment,
+        }
+    }
+}
+
+/// Resolves `path` relatively to the patcher's working directory.
+fn absolute_path(path: &str) -> Result<PathBuf> {
+    Ok(env::current_dir()?.join(path))
 }
 
 // Note: Taken from the rustup project
@@ -58,10 +268,11 @@ mod windows {
 
     /// This function is required to start processes that require elevation, from
     /// a non-elevated process.
-    pub fn win32_spawn_process_runas<S>(path: S, parameter: S) -> Result<bool>
-    where
-        S: AsRef<OsStr>,
-    {
+    pub fn win32_spawn_process_runas(
+        path: &OsStr,
+        parameter: &OsStr,
+        directory: Option<&OsStr>,
+    ) -> Result<bool> {
         use std::ptr;
         use winapi::ctypes::c_int;
         use winapi::shared::minwindef::{BOOL, ULONG};
@@ -73,11 +284,12 @@ mod windows {
         const SW_SHOW: c_int = 5;
 
         // Note: It seems `path` has to be absolute for the class overwrite to work
-        let exe_path = std::env::current_dir()?.join(path.as_ref());
+        let exe_path = std::env::current_dir()?.join(path);
         let exe_path = to_u16s(exe_path.to_str().unwrap_or(""))?;
         let parameter = to_u16s(parameter)?;
         let operation = to_u16s("runas")?;
         let class = to_u16s("exefile")?;
+        let directory = directory.map(to_u16s).transpose()?;
         let mut execute_info = SHELLEXECUTEINFOW {
             cbSize: std::mem::size_of::<SHELLEXECUTEINFOW>() as u32,
             fMask: SEE_MASK_CLASSNAME,
@@ -85,7 +297,7 @@ mod windows {
             lpVerb: operation.as_ptr(),
             lpFile: exe_path.as_ptr(),
             lpParameters: parameter.as_ptr(),
-            lpDirectory: ptr::null_mut(),
+            lpDirectory: directory.as_ref().map_or(ptr::null(), |d| d.as_ptr()),
             nShow: SW_SHOW,
             hInstApp: ptr::null_mut(),
             lpIDList: ptr::null_mut(),
@@ -100,3 +312,98 @@ mod windows {
         Ok(result != 0)
     }
 }
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::config::RunnerKind;
+
+    fn runner_configuration(kind: RunnerKind) -> RunnerConfiguration {
+        RunnerConfiguration {
+            kind,
+            command: None,
+            arguments: vec![],
+            prefix: Some("prefix".to_string()),
+            environment: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_runner_command() {
+        let prefix = env::current_dir()
+            .unwrap()
+            .join("prefix")
+            .to_string_lossy()
+            .into_owned();
+        let mut runner = runner_configuration(RunnerKind::Wine);
+        runner
+            .environment
+            .insert("WINEDEBUG".to_string(), "-all".to_string());
+        let command = RunnerCommand::new(&runner);
+        assert_eq!("wine", command.program);
+        assert!(command.arguments.is_empty());
+        assert_eq!(
+            vec![
+                ("WINEPREFIX".to_string(), prefix.clone()),
+                ("WINEDEBUG".to_string(), "-all".to_string())
+            ],
+            command.environment
+        );
+
+        let mut runner = runner_configuration(RunnerKind::Proton);
+        runner.command = Some("/opt/proton/proton".to_string());
+        runner.arguments = vec!["--verbose".to_string()];
+        let command = RunnerCommand::new(&runner);
+        assert_eq!("/opt/proton/proton", command.program);
+        assert_eq!(vec!["run", "--verbose"], command.arguments);
+        assert_eq!(
+            ("STEAM_COMPAT_DATA_PATH".to_string(), prefix),
+            command.environment[0]
+        );
+    }
+
+    #[test]
+    fn test_start_executable() {
+        let start_options = || StartOptions {
+            working_directory: None,
+            environment: None,
+            runner: None,
+        };
+        let startup_delay = Duration::from_secs(5);
+        let process = start_executable("true", Vec::<String>::new(), start_options())
+            .unwrap()
+            .unwrap();
+        assert!(process.wait_for_startup(startup_delay).is_ok());
+        let process = start_executable("false", Vec::<String>::new(), start_options())
+            .unwrap()
+            .unwrap();
+        assert!(process.wait_for_startup(startup_delay).is_err());
+        let process = start_executable("sleep", vec!["5"], start_options())
+            .unwrap()
+            .unwrap();
+        assert!(process.wait_for_startup(Duration::from_millis(200)).is_ok());
+
+        // The runner is started with the executable's path as first argument
+        let mut runner = runner_configuration(RunnerKind::Custom);
+        runner.command = Some("test".to_string());
+        runner.arguments = vec!["-f".to_string()];
+        let options = StartOptions {
+            runner: Some(&runner),
+            ..start_options()
+        };
+        let process = start_executable("Cargo.toml", Vec::<String>::new(), options)
+            .unwrap()
+            .unwrap();
+        assert!(process.wait_for_startup(startup_delay).is_ok());
+
+        runner.command = Some("rpatchur-missing-runner".to_string());
+        let options = StartOptions {
+            runner: Some(&runner),
+            ..start_options()
+        };
+        let err = start_executable("ragexe.exe", Vec::<String>::new(), options)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("rpatchur-missing-runner"));
+    }
+}
@@ -0,0 +1,183 @@
+//! Templates of the arguments given to the game client.
+//!
+//! Arguments can contain placeholders, such as `{login}`, which are replaced
+//! when the client is started. `{env:NAME}` is replaced with the value of the
+//! `NAME` environment variable. Literal braces are written `{{` and `}}`.
+
+use std::env;
+
+use anyhow::{anyhow, Result};
+
+/// Placeholders available when playing without logging in
+pub const PLAY_PLACEHOLDERS: &[&str] = &["server", "lang"];
+/// Placeholders available when logging in through the `login` function
+pub const LOGIN_PLACEHOLDERS: &[&str] = &["login", "token", "server", "lang"];
+
+/// Values the placeholders are replaced with.
+#[derive(Default)]
+pub struct TemplateValues<'a> {
+    pub login: Option<&'a str>,
+    pub token: Option<&'a str>, // One-time token, or password if there's no login endpoint
+    pub server: Option<&'a str>,
+    pub lang: Option<&'a str>,
+}
+
+impl<'a> TemplateValues<'a> {
+    fn get(&self, placeholder: &str) -> Result<String> {
+        if let Some(var_name) = placeholder.strip_prefix("env:") {
+            return env::var(var_name)
+                .map_err(|_| anyhow!("Environment variable '{}' is not set", var_name));
+        }
+        let value = match placeholder {
+            "login" => self.login,
+            "token" => self.token,
+            "server" => self.server,
+            "lang" => self.lang,
+            _ => None,
+        };
+        value
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("No value for placeholder '{{{}}}'", placeholder))
+    }
+}
+
+/// Part of an argument
+enum Segment<'a> {
+    Text(char),
+    Placeholder(&'a str), // Name of the placeholder, without braces
+}
+
+/// Checks that `template` only contains well-formed placeholders taken from
+/// `placeholders` (or environment variables).
+pub fn validate_template(template: &[String], placeholders: &[&str]) -> Result<()> {
+    for argument in template {
+        for segment in parse_argument(argument)? {
+            if let Segment::Placeholder(name) = segment {
+                if !name.starts_with("env:") && !placeholders.contains(&name) {
+                    return Err(anyhow!(
+                        "Unknown placeholder '{{{}}}' in argument '{}'",
+                        name,
+                        argument
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if one of the arguments of `template` uses `placeholder`.
+pub fn uses_placeholder(template: &[String], placeholder: &str) -> bool {
+    template.iter().any(|argument| {
+        parse_argument(argument)
+            .map(|segments| {
+                segments.iter().any(|segment| match segment {
+                    Segment::Placeholder(name) => *name == placeholder,
+                    Segment::Text(_) => false,
+                })
+            })
+            .unwrap_or(false)
+    })
+}
+
+/// Replaces the placeholders of `template` with `values`.
+pub fn render_template(template: &[String], values: &TemplateValues) -> Result<Vec<String>> {
+    template
+        .iter()
+        .map(|argument| {
+            let mut rendered = String::new();
+            for segment in parse_argument(argument)? {
+                match segment {
+                    Segment::Text(c) => rendered.push(c),
+                    Segment::Placeholder(name) => rendered.push_str(&values.get(name)?),
+                }
+            }
+            Ok(rendered)
+        })
+        .collect()
+}
+
+fn parse_argument(argument: &str) -> Result<Vec<Segment<'_>>> {
+    let mut segments = vec![];
+    let mut chars = argument.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                chars.next();
+                segments.push(Segment::Text('{'));
+            }
+            '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                chars.next();
+                segments.push(Segment::Text('}'));
+            }
+            '{' => {
+                let end = argument[i..]
+                    .find('}')
+                    .map(|len| i + len)
+                    .ok_or_else(|| anyhow!("Unclosed placeholder in argument '{}'", argument))?;
+                let name = &argument[i + 1..end];
+                if name.is_empty() || name.contains('{') {
+                    return Err(anyhow!("Invalid placeholder in argument '{}'", argument));
+                }
+                segments.push(Segment::Placeholder(name));
+                // Skip the placeholder's name and closing brace
+                for (j, _) in chars.by_ref() {
+                    if j == end {
+                        break;
+                    }
+                }
+            }
+            '}' => return Err(anyhow!("Unmatched '}}' in argument '{}'", argument)),
+            c => segments.push(Segment::Text(c)),
+        }
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(arguments: &[&str]) -> Vec<String> {
+        arguments.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_validate_template() {
+        let login_template = template(&["-t:{token}", "{login}", "{server}", "/lang:{lang}"]);
+        assert!(validate_template(&login_template, LOGIN_PLACEHOLDERS).is_ok());
+        assert!(validate_template(&login_template, PLAY_PLACEHOLDERS).is_err());
+        assert!(validate_template(&template(&["{env:HOME}", "{{}}"]), PLAY_PLACEHOLDERS).is_ok());
+        assert!(validate_template(&template(&["{password}"]), LOGIN_PLACEHOLDERS).is_err());
+        assert!(validate_template(&template(&["{login"]), LOGIN_PLACEHOLDERS).is_err());
+        assert!(validate_template(&template(&["login}"]), LOGIN_PLACEHOLDERS).is_err());
+        assert!(validate_template(&template(&["{}"]), LOGIN_PLACEHOLDERS).is_err());
+        assert!(uses_placeholder(&login_template, "lang"));
+        assert!(!uses_placeholder(&template(&["1sak1"]), "lang"));
+    }
+
+    #[test]
+    fn test_render_template() {
+        let values = TemplateValues {
+            login: Some("user"),
+            token: Some("abcdef"),
+            server: Some("server"),
+            lang: None,
+        };
+        assert_eq!(
+            vec!["-t:abcdef", "user", "server", "{1sak1}"],
+            render_template(
+                &template(&["-t:{token}", "{login}", "{server}", "{{1sak1}}"]),
+                &values
+            )
+            .unwrap()
+        );
+        env::set_var("RPATCHUR_TEST_VAR", "value");
+        assert_eq!(
+            vec!["--var=value"],
+            render_template(&template(&["--var={env:RPATCHUR_TEST_VAR}"]), &values).unwrap()
+        );
+        assert!(render_template(&template(&["{lang}"]), &values).is_err());
+        assert!(render_template(&template(&["{env:RPATCHUR_UNSET_VAR}"]), &values).is_err());
+    }
+}
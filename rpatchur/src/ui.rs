@@ -1,59 +1,66 @@
-use std::fs;
+use std::env;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::patcher::{get_patcher_name, PatcherCommand, PatcherConfiguration};
-use crate::process::start_executable;
-use serde::Deserialize;
-use serde_json::Value;
+use crate::bridge::{command_name, BridgePolicy};
+use crate::config::{
+    LaunchProfileConfiguration, LoginConfiguration, PatcherConfiguration, WebConfiguration,
+    PLAY_PROFILE_NAME, SETUP_PROFILE_NAME,
+};
+use crate::login::{request_login_token, LoginEvent};
+use crate::process::{start_executable, LaunchEvent, StartOptions};
+use crate::template::{render_template, TemplateValues};
+use anyhow::Context;
+use anyhow::{anyhow, Result};
+use rpatchur_core::{
+    get_patch_history, get_pending_patches, reset_cache, AppliedPatch, PatcherCommand,
+    PatcherEvent, PatcherHandle,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tinyfiledialogs as tfd;
+use tokio::runtime;
+use url::Url;
 use web_view::{Content, Handle, WebView};
 
-/// 'Opaque" struct that can be used to update the UI.
-pub struct UiController {
+/// Script that forwards the events to `rpatchur.onEvent` (or to the legacy
+/// `patchingStatus*` functions)
+const EVENT_DISPATCHER: &str = include_str!("../resources/event_dispatcher.js");
+/// Minimal UI used when the remote UI and the fallback UI are unavailable
+const OFFLINE_UI: &str = include_str!("../resources/offline.html");
+const DEFAULT_INDEX_TIMEOUT_SECS: u64 = 5;
+/// Time during which started executables are watched for failures
+const STARTUP_CHECK_DELAY: Duration = Duration::from_secs(3);
+
+/// 'Opaque" struct that can be used to update the web view.
+pub struct WebViewController {
     web_view_handle: Handle<WebViewUserData>,
 }
-impl UiController {
-    pub fn new(web_view: &WebView<'_, WebViewUserData>) -> UiController {
-        UiController {
+impl WebViewController {
+    pub fn new(web_view: &WebView<'_, WebViewUserData>) -> WebViewController {
+        WebViewController {
             web_view_handle: web_view.handle(),
         }
     }
 
-    /// Allows another thread to indicate the current status of the patching process.
-    ///
-    /// This updates the UI with useful information.
-    pub fn dispatch_patching_status(&self, status: PatchingStatus) {
+    /// Updates the UI with the events emitted by the patcher.
+    pub fn dispatch_event(&self, event: PatcherEvent) {
         if let Err(e) = self.web_view_handle.dispatch(move |webview| {
-            let result = match status {
-                PatchingStatus::Ready => webview.eval("patchingStatusReady()"),
-                PatchingStatus::Error(msg) => {
-                    webview.eval(&format!("patchingStatusError(\"{}\")", msg))
-                }
-                PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => {
-                    webview.eval(&format!(
-                        "patchingStatusDownloading({}, {}, {})",
-                        nb_downloaded, nb_total, bytes_per_sec
-                    ))
-                }
-                PatchingStatus::InstallationInProgress(nb_installed, nb_total) => webview.eval(
-                    &format!("patchingStatusInstalling({}, {})", nb_installed, nb_total),
-                ),
-                PatchingStatus::ManualPatchApplied(name) => {
-                    webview.eval(&format!("patchingStatusPatchApplied(\"{}\")", name))
+            let user_data = webview.user_data_mut();
+            match &event {
+                PatcherEvent::PatchingStarted => user_data.patching_in_progress = true,
+                PatcherEvent::PatchingStopped => user_data.patching_in_progress = false,
+                PatcherEvent::PatchServersInUse { names } => {
+                    user_data.patch_servers_in_use = names.clone()
                 }
-            };
-            if let Err(e) = result {
-                log::warn!("Failed to dispatch patching status: {}.", e);
+                PatcherEvent::Ready => user_data.pending_patch_count = Some(0),
+                _ => {}
+            }
+            send_event(webview, &event);
+            // The patcher must be restarted
+            if let PatcherEvent::RelaunchRequired { .. } = event {
+                webview.exit();
             }
-            Ok(())
-        }) {
-            log::warn!("Failed to dispatch patching status: {}.", e);
-        }
-    }
-
-    pub fn set_patch_in_progress(&self, value: bool) {
-        if let Err(e) = self.web_view_handle.dispatch(move |webview| {
-            webview.user_data_mut().patching_in_progress = value;
             Ok(())
         }) {
             log::warn!("Failed to dispatch patching status: {}.", e);
@@ -61,47 +68,151 @@ impl UiController {
     }
 }
 
-/// Used to indicate the current status of the patching process.
-pub enum PatchingStatus {
-    Ready,
-    Error(String),                         // Error message
-    DownloadInProgress(usize, usize, u64), // Downloaded files, Total number, Bytes per second
-    InstallationInProgress(usize, usize),  // Installed patches, Total number
-    ManualPatchApplied(String),            // Patch file name
-}
-
 pub struct WebViewUserData {
     patcher_config: PatcherConfiguration,
-    patching_thread_tx: flume::Sender<PatcherCommand>,
+    bridge_policy: BridgePolicy,
+    patcher: PatcherHandle,
     patching_in_progress: bool,
+    patch_servers_in_use: Vec<String>,
+    pending_patch_count: Option<usize>, // Unknown until checked
 }
 impl WebViewUserData {
     pub fn new(
         patcher_config: PatcherConfiguration,
-        patching_thread_tx: flume::Sender<PatcherCommand>,
+        bridge_policy: BridgePolicy,
+        patcher: PatcherHandle,
     ) -> WebViewUserData {
         WebViewUserData {
             patcher_config,
-            patching_thread_tx,
+            bridge_policy,
+            patcher,
             patching_in_progress: false,
+            patch_servers_in_use: vec![],
+            pending_patch_count: None,
         }
     }
 }
 impl Drop for WebViewUserData {
     fn drop(&mut self) {
         // Ask the patching thread to stop whenever WebViewUserData is dropped
-        let _res = self.patching_thread_tx.try_send(PatcherCommand::Quit);
+        let _res = self.patcher.send(PatcherCommand::Quit);
     }
 }
 
+/// Page displayed in the window
+#[derive(Debug, PartialEq)]
+enum UiSource {
+    Remote,            // Page located at web.index_url
+    LocalFile(String), // URL of web.fallback_index_path
+    Embedded,          // Offline UI embedded in the patcher
+}
+
+/// Selects the page to display.
+///
+/// The remote UI is used if it can be retrieved within `web.index_timeout`.
+/// Otherwise, the fallback UI is used if it exists, or the embedded one.
+fn select_ui_source(web_config: &WebConfiguration) -> UiSource {
+    let index_timeout = Duration::from_secs(
+        web_config
+            .index_timeout
+            .unwrap_or(DEFAULT_INDEX_TIMEOUT_SECS),
+    );
+    let err = match probe_index(&web_config.index_url, index_timeout) {
+        Ok(()) => return UiSource::Remote,
+        Err(e) => e,
+    };
+    log::warn!("The remote UI is unavailable: {:#}", err);
+    if let Some(fallback_index_path) = &web_config.fallback_index_path {
+        match local_file_url(fallback_index_path) {
+            Ok(url) => return UiSource::LocalFile(url.to_string()),
+            Err(e) => log::warn!("Cannot use the fallback UI: {:#}", e),
+        }
+    }
+    UiSource::Embedded
+}
+
+/// Selects the page to display from another thread, so that the window can be
+/// shown in the meantime, and loads it.
+pub fn load_ui(webview: &WebView<'_, WebViewUserData>) {
+    let web_config = webview.user_data().patcher_config.web.clone();
+    let web_view_handle = webview.handle();
+    std::thread::spawn(move || {
+        let ui_source = select_ui_source(&web_config);
+        let res = web_view_handle.dispatch(move |webview| {
+            show_ui_source(webview, ui_source);
+            Ok(())
+        });
+        if let Err(e) = res {
+            log::warn!("Failed to load the UI: {}.", e);
+        }
+    });
+}
+
+/// Replaces the embedded UI with the selected page.
+fn show_ui_source(webview: &mut WebView<WebViewUserData>, ui_source: UiSource) {
+    let (url, local_page) = match ui_source {
+        UiSource::Remote => (
+            webview.user_data().patcher_config.web.index_url.clone(),
+            false,
+        ),
+        UiSource::LocalFile(url) => (url, true),
+        // Already shown
+        UiSource::Embedded => return,
+    };
+    // Only the new page can use the bridge
+    if let Err(e) = webview
+        .user_data_mut()
+        .bridge_policy
+        .expect_page(local_page)
+    {
+        log::warn!("Failed to load the UI: {:#}", e);
+        return;
+    }
+    if let Err(e) = webview.eval(&format!("window.location.replace({})", to_js_literal(&url))) {
+        log::warn!("Failed to load the UI: {}.", e);
+    }
+}
+
+/// Checks that the remote UI can be retrieved within `timeout`.
+///
+/// Only the headers are requested, the page itself is retrieved by the web
+/// view.
+fn probe_index(index_url: &str, timeout: Duration) -> Result<()> {
+    let url = Url::parse(index_url).with_context(|| "Invalid index URL")?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        // Local pages are always available
+        return Ok(());
+    }
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    tokio_rt.block_on(async {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        client.head(url).send().await?.error_for_status()?;
+        Ok(())
+    })
+}
+
+/// Returns the file URL of `path`, relative to the working directory.
+fn local_file_url(path: &str) -> Result<Url> {
+    let path = env::current_dir()?.join(path);
+    if !path.is_file() {
+        return Err(anyhow!("'{}' doesn't exist", path.display()));
+    }
+    Url::from_file_path(&path).map_err(|_| anyhow!("Invalid path '{}'", path.display()))
+}
+
 /// Creates a `WebView` object with the appropriate settings for our needs.
-pub fn build_webview<'a>(
-    title: &'a str,
+///
+/// The embedded UI is shown until `load_ui` has selected the page to display.
+pub fn build_webview(
+    title: &str,
     user_data: WebViewUserData,
-) -> web_view::WVResult<WebView<'a, WebViewUserData>> {
+) -> web_view::WVResult<WebView<'_, WebViewUserData>> {
     web_view::builder()
         .title(title)
-        .content(Content::Url(user_data.patcher_config.web.index_url.clone()))
+        .content(Content::Html(OFFLINE_UI.to_string()))
         .size(
             user_data.patcher_config.window.width,
             user_data.patcher_config.window.height,
@@ -109,54 +220,90 @@ pub fn build_webview<'a>(
         .resizable(user_data.patcher_config.window.resizable)
         .user_data(user_data)
         .invoke_handler(|webview, arg| {
-            match arg {
-                "play" => handle_play(webview),
-                "setup" => handle_setup(webview),
-                "exit" => handle_exit(webview),
-                "start_update" => handle_start_update(webview),
-                "cancel_update" => handle_cancel_update(webview),
-                "reset_cache" => handle_reset_cache(webview),
-                "manual_patch" => handle_manual_patch(webview),
-                request => handle_json_request(webview, request),
+            match webview.user_data().bridge_policy.untag_request(arg) {
+                Some((origin, request)) => handle_tagged_request(webview, origin, request),
+                None => hand_out_session_key(webview, arg),
             }
             Ok(())
         })
         .build()
 }
 
+/// Gives the top-level document a `window.external.invoke` function that tags
+/// its requests with the session key and its origin, and sends `request` again
+/// through it.
+///
+/// The session key is only handed out once, to the page loaded by the patcher
+/// if its origin is expected (see the `bridge` module).
+fn hand_out_session_key(webview: &mut WebView<WebViewUserData>, request: &str) {
+    let bridge_policy = &mut webview.user_data_mut().bridge_policy;
+    let message_prefix = match bridge_policy.hand_out_message_prefix() {
+        Some(message_prefix) => message_prefix,
+        None => {
+            log::warn!("Refused untagged request from a page that wasn't loaded by the patcher");
+            return;
+        }
+    };
+    let expected_origins = bridge_policy.expected_origins();
+    // Scripts are evaluated in the top-level document, whose origin cannot be
+    // forged by the frames it embeds
+    let script = format!(
+        "(function (external, prefix, origins, request) {{\
+            if (origins.indexOf(window.location.origin) < 0) {{ return; }}\
+            window.external = {{ invoke: function (request) {{\
+                external.invoke(prefix + window.location.origin + \"\\n\" + request);\
+            }} }};\
+            window.external.invoke(request);\
+        }})(window.external, {}, {}, {});",
+        to_js_literal(&message_prefix),
+        to_js_literal(&expected_origins),
+        to_js_literal(&request)
+    );
+    if let Err(e) = webview.eval(&script) {
+        log::warn!("Failed to hand out the session key: {}.", e);
+    }
+}
+
+/// Checks that the page and the command are allowed to use the bridge and
+/// dispatches the request.
+fn handle_tagged_request(webview: &mut WebView<WebViewUserData>, origin: &str, request: &str) {
+    let bridge_policy = &webview.user_data().bridge_policy;
+    if !bridge_policy.is_origin_allowed(origin) {
+        log::warn!("Refused request from unauthorized origin '{}'", origin);
+        return;
+    }
+    match command_name(request) {
+        Some(command) if !bridge_policy.is_command_allowed(&command) => {
+            log::warn!("Refused unauthorized command '{}'", command);
+            return;
+        }
+        _ => {}
+    }
+    match request {
+        "play" => handle_play(webview),
+        "setup" => handle_setup(webview),
+        "exit" => handle_exit(webview),
+        "start_update" => handle_start_update(webview),
+        "cancel_update" => handle_cancel_update(webview),
+        "reset_cache" => handle_reset_cache(webview),
+        "patch_history" => handle_patch_history(webview),
+        "manual_patch" => handle_manual_patch(webview),
+        request => handle_json_request(webview, request),
+    }
+}
+
 /// Opens the configured game client with the configured arguments.
 ///
 /// This function can create elevated processes on Windows with UAC activated.
 fn handle_play(webview: &mut WebView<WebViewUserData>) {
-    let client_arguments = webview.user_data().patcher_config.play.arguments.clone();
-    start_game_client(webview, &client_arguments);
+    start_launch_profile_by_name(webview, PLAY_PROFILE_NAME);
 }
 
 /// Opens the configured 'Setup' software with the configured arguments.
 ///
 /// This function can create elevated processes on Windows with UAC activated.
 fn handle_setup(webview: &mut WebView<WebViewUserData>) {
-    let setup_exe: &String = &webview.user_data().patcher_config.setup.path;
-    let setup_arguments = &webview.user_data().patcher_config.setup.arguments;
-    let exit_on_success = webview
-        .user_data()
-        .patcher_config
-        .setup
-        .exit_on_success
-        .unwrap_or(false);
-    match start_executable(setup_exe, setup_arguments) {
-        Ok(success) => {
-            if success {
-                log::trace!("Setup software started");
-                if exit_on_success {
-                    webview.exit();
-                }
-            }
-        }
-        Err(e) => {
-            log::warn!("Failed to start setup software: {}", e);
-        }
-    }
+    start_launch_profile_by_name(webview, SETUP_PROFILE_NAME);
 }
 
 /// Exits the patcher cleanly.
@@ -175,35 +322,42 @@ fn handle_start_update(webview: &mut WebView<WebViewUserData>) {
         return;
     }
 
-    let send_res = webview
-        .user_data_mut()
-        .patching_thread_tx
-        .send(PatcherCommand::StartUpdate);
-    if send_res.is_ok() {
+    if webview
+        .user_data()
+        .patcher
+        .send(PatcherCommand::StartUpdate)
+    {
         log::trace!("Sent StartUpdate command to patching thread");
     }
 }
 
 /// Cancels the patching task/thread.
 fn handle_cancel_update(webview: &mut WebView<WebViewUserData>) {
-    if webview
-        .user_data_mut()
-        .patching_thread_tx
-        .send(PatcherCommand::CancelUpdate)
-        .is_ok()
-    {
+    if webview.user_data().patcher.cancel_update() {
         log::trace!("Sent CancelUpdate command to patching thread");
     }
 }
 
 /// Resets the patcher cache (which is used to keep track of already applied
 /// patches).
-fn handle_reset_cache(_webview: &mut WebView<WebViewUserData>) {
-    if let Ok(patcher_name) = get_patcher_name() {
-        let cache_file_path = PathBuf::from(patcher_name).with_extension("dat");
-        if let Err(e) = fs::remove_file(cache_file_path) {
-            log::warn!("Failed to remove the cache file: {}", e);
+fn handle_reset_cache(webview: &mut WebView<WebViewUserData>) {
+    if let Err(e) = reset_cache(&webview.user_data().patcher_config.engine()) {
+        log::warn!("Failed to remove the cache file: {:#}", e);
+    }
+}
+
+/// Sends the list of applied patches to the UI.
+fn handle_patch_history(webview: &mut WebView<WebViewUserData>) {
+    let patch_history = match get_patch_history(&webview.user_data().patcher_config.engine()) {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Failed to read the patch history: {:#}", e);
+            vec![]
         }
+    };
+    let res = webview.eval(&format!("patchHistory({})", to_js_literal(&patch_history)));
+    if let Err(e) = res {
+        log::warn!("Failed to dispatch patch history: {}.", e);
     }
 }
 
@@ -226,10 +380,9 @@ fn handle_manual_patch(webview: &mut WebView<WebViewUserData>) {
     if let Some(path) = opt_path {
         log::info!("Requesting manual patch '{}'", path);
         if webview
-            .user_data_mut()
-            .patching_thread_tx
+            .user_data()
+            .patcher
             .send(PatcherCommand::ApplyPatch(PathBuf::from(path)))
-            .is_ok()
         {
             log::trace!("Sent ApplyPatch command to patching thread");
         }
@@ -248,9 +401,19 @@ fn handle_json_request(webview: &mut WebView<WebViewUserData>, request: &str) {
             let function_name = json_req["function"].as_str();
             if let Some(function_name) = function_name {
                 let function_params = json_req["parameters"].clone();
+                // Identifies the response to queries
+                let request_id = json_req["id"].clone();
                 match function_name {
                     "login" => handle_login(webview, function_params),
-                    "open_url" => handle_open_url(function_params),
+                    "launch" => handle_launch(webview, function_params),
+                    "open_url" => handle_open_url(webview, function_params),
+                    "set_download_speed_limit" => {
+                        handle_set_download_speed_limit(webview, function_params)
+                    }
+                    "get_state" => handle_get_state(webview, request_id),
+                    "get_config_public" => handle_get_config_public(webview, request_id),
+                    "check_for_updates" => handle_check_for_updates(webview, request_id),
+                    "get_patch_history" => handle_get_patch_history(webview, request_id),
                     _ => {
                         log::error!("Unknown function '{}'", function_name);
                     }
@@ -268,28 +431,81 @@ struct LoginParameters {
 }
 
 /// Launches the game client with the given credentials
+///
+/// If a login endpoint is configured, the credentials are exchanged for a
+/// one-time token first.
 fn handle_login(webview: &mut WebView<WebViewUserData>, parameters: Value) {
     let result: serde_json::Result<LoginParameters> = serde_json::from_value(parameters);
-    match result {
-        Err(e) => log::error!("Invalid arguments given for 'login': {}", e),
-        Ok(login_params) => {
-            // Push credentials to the list of arguments first
-            let mut play_arguments: Vec<String> = vec![
-                format!("-t:{}", login_params.password),
-                login_params.login,
-                "server".to_string(),
-            ];
-            play_arguments.extend(
-                webview
-                    .user_data()
-                    .patcher_config
-                    .play
-                    .arguments
-                    .iter()
-                    .cloned(),
-            );
-            start_game_client(webview, &play_arguments);
+    let login_config = webview.user_data().patcher_config.login.clone();
+    match (result, login_config) {
+        (Err(e), _) => log::error!("Invalid arguments given for 'login': {}", e),
+        (Ok(login_params), Some(login_config)) => {
+            login_with_token(webview, login_config, login_params)
+        }
+        (Ok(login_params), None) => {
+            // The password is given to the client as the token
+            start_game_client_with_login(webview, &login_params.login, &login_params.password);
+        }
+    }
+}
+
+/// Requests a one-time token from the login endpoint and launches the game
+/// client with it.
+///
+/// The login endpoint is queried from another thread, so that the UI isn't
+/// blocked in the meantime. The UI is notified with `LoginEvent`s.
+fn login_with_token(
+    webview: &mut WebView<WebViewUserData>,
+    login_config: LoginConfiguration,
+    login_params: LoginParameters,
+) {
+    send_event(webview, &LoginEvent::Started);
+    let web_view_handle = webview.handle();
+    std::thread::spawn(move || {
+        let token_result = match runtime::Builder::new_current_thread().enable_all().build() {
+            Ok(tokio_rt) => tokio_rt.block_on(request_login_token(
+                &login_config,
+                &login_params.login,
+                &login_params.password,
+            )),
+            Err(e) => {
+                log::error!("Failed to build a tokio runtime: {}", e);
+                return;
+            }
+        };
+        let dispatch_res = web_view_handle.dispatch(move |webview| {
+            match token_result {
+                Err(e) => {
+                    log::warn!("Login failed: {}", e);
+                    send_event(webview, &LoginEvent::Failed(e));
+                }
+                Ok(token) => {
+                    send_event(webview, &LoginEvent::Succeeded);
+                    start_game_client_with_login(webview, &login_params.login, &token);
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = dispatch_res {
+            log::warn!("Failed to dispatch login result: {}.", e);
         }
+    });
+}
+
+/// Parameters expected for the launch function
+#[derive(Deserialize)]
+struct LaunchParameters {
+    profile: String, // Name of the launch profile
+}
+
+/// Starts a launch profile.
+///
+/// This function can create elevated processes on Windows with UAC activated.
+fn handle_launch(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<LaunchParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => log::error!("Invalid arguments given for 'launch': {}", e),
+        Ok(launch_params) => start_launch_profile_by_name(webview, &launch_params.profile),
     }
 }
 
@@ -299,12 +515,23 @@ struct OpenUrlParameters {
     url: String,
 }
 
-/// Opens an URL with the native URL Handler
-fn handle_open_url(parameters: Value) {
+/// Opens an URL with the native URL Handler.
+///
+/// Only URLs whose scheme is allowed by the bridge policy are opened, so that
+/// pages cannot start local executables.
+fn handle_open_url(webview: &mut WebView<WebViewUserData>, parameters: Value) {
     let result: serde_json::Result<OpenUrlParameters> = serde_json::from_value(parameters);
-    match result {
-        Err(e) => log::error!("Invalid arguments given for 'open_url': {}", e),
-        Ok(params) => match open::that(params.url) {
+    let url = result
+        .map_err(|e| format!("Invalid arguments given for 'open_url': {}", e))
+        .and_then(|params| {
+            let bridge_policy = &webview.user_data().bridge_policy;
+            bridge_policy
+                .check_url(&params.url)
+                .map_err(|e| format!("Refused to open URL: {:#}", e))
+        });
+    match url {
+        Err(msg) => log::error!("{}", msg),
+        Ok(url) => match open::that(url.as_str()) {
             Ok(exit_status) => {
                 if !exit_status.success() {
                     if let Some(code) = exit_status.code() {
@@ -319,25 +546,358 @@ fn handle_open_url(parameters: Value) {
     }
 }
 
-fn start_game_client(webview: &mut WebView<WebViewUserData>, client_arguments: &[String]) {
-    let client_exe: &String = &webview.user_data().patcher_config.play.path;
-    let exit_on_success = webview
-        .user_data()
-        .patcher_config
-        .play
-        .exit_on_success
-        .unwrap_or(true);
-    match start_executable(client_exe, client_arguments) {
-        Ok(success) => {
-            if success {
-                log::trace!("Client started");
-                if exit_on_success {
-                    webview.exit();
-                }
+/// Parameters expected for the set_download_speed_limit function
+#[derive(Deserialize)]
+struct SetDownloadSpeedLimitParameters {
+    bytes_per_sec: Option<u64>, // None or 0 for unlimited
+}
+
+/// Changes the maximum download speed, even if an update is in progress
+fn handle_set_download_speed_limit(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<SetDownloadSpeedLimitParameters> =
+        serde_json::from_value(parameters);
+    match result {
+        Err(e) => log::error!(
+            "Invalid arguments given for 'set_download_speed_limit': {}",
+            e
+        ),
+        Ok(params) => {
+            if webview
+                .user_data()
+                .patcher
+                .send(PatcherCommand::SetDownloadSpeedLimit(params.bytes_per_sec))
+            {
+                log::trace!("Sent SetDownloadSpeedLimit command to patching thread");
             }
         }
+    }
+}
+
+/// State of the patcher, as returned by the get_state function
+#[derive(Serialize)]
+struct PatcherState {
+    version: &'static str,
+    patching_in_progress: bool,
+    last_applied_patch: Option<AppliedPatch>,
+    patch_servers_in_use: Vec<String>,
+    pending_patch_count: Option<usize>, // Unknown until checked for updates
+}
+
+/// Sends the current state of the patcher to the UI
+fn handle_get_state(webview: &mut WebView<WebViewUserData>, request_id: Value) {
+    let result = get_patch_history(&webview.user_data().patcher_config.engine())
+        .map(|patch_history| {
+            let user_data = webview.user_data();
+            PatcherState {
+                version: env!("CARGO_PKG_VERSION"),
+                patching_in_progress: user_data.patching_in_progress,
+                last_applied_patch: patch_history.last().cloned(),
+                patch_servers_in_use: user_data.patch_servers_in_use.clone(),
+                pending_patch_count: user_data.pending_patch_count,
+            }
+        })
+        .map_err(|e| format!("Failed to read the patch history: {:#}", e));
+    send_response(webview, request_id, result);
+}
+
+/// Settings that can be shown to users, as returned by the
+/// get_config_public function
+#[derive(Serialize)]
+struct PublicConfiguration {
+    title: String,
+    patch_servers: Vec<String>,
+    preferred_patch_server: Option<String>,
+    max_parallel_downloads: Option<usize>,
+    download_speed_limit: Option<u64>,
+}
+
+/// Sends the settings that can be shown to users to the UI
+fn handle_get_config_public(webview: &mut WebView<WebViewUserData>, request_id: Value) {
+    let config = &webview.user_data().patcher_config;
+    let public_config = PublicConfiguration {
+        title: config.window.title.clone(),
+        patch_servers: config
+            .web
+            .engine
+            .patch_servers
+            .iter()
+            .map(|server| server.name.clone())
+            .collect(),
+        preferred_patch_server: config.web.engine.preferred_patch_server.clone(),
+        max_parallel_downloads: config.patching.max_parallel_downloads,
+        download_speed_limit: config.patching.download_speed_limit,
+    };
+    send_response(webview, request_id, Ok(public_config));
+}
+
+/// Patch that hasn't been applied yet, as returned by the check_for_updates
+/// function
+#[derive(Serialize)]
+struct PendingPatch {
+    index: usize,
+    file_name: String,
+}
+
+/// Fetches the patch list and sends the patches that haven't been applied yet
+/// to the UI.
+///
+/// Patch servers are queried from another thread, so that the UI isn't
+/// blocked in the meantime.
+fn handle_check_for_updates(webview: &mut WebView<WebViewUserData>, request_id: Value) {
+    let engine_config = webview.user_data().patcher_config.engine();
+    let web_view_handle = webview.handle();
+    std::thread::spawn(move || {
+        let result = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .with_context(|| "Failed to build a tokio runtime")
+            .and_then(|tokio_rt| tokio_rt.block_on(get_pending_patches(&engine_config)))
+            .map(|patch_list| {
+                patch_list
+                    .into_iter()
+                    .map(|patch| PendingPatch {
+                        index: patch.index,
+                        file_name: patch.file_name,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .map_err(|e| format!("Failed to check for updates: {:#}", e));
+        let dispatch_res = web_view_handle.dispatch(move |webview| {
+            if let Ok(pending_patches) = &result {
+                webview.user_data_mut().pending_patch_count = Some(pending_patches.len());
+            }
+            send_response(webview, request_id, result);
+            Ok(())
+        });
+        if let Err(e) = dispatch_res {
+            log::warn!("Failed to dispatch response: {}.", e);
+        }
+    });
+}
+
+/// Sends the list of applied patches to the UI
+fn handle_get_patch_history(webview: &mut WebView<WebViewUserData>, request_id: Value) {
+    let result = get_patch_history(&webview.user_data().patcher_config.engine())
+        .map_err(|e| format!("Failed to read the patch history: {:#}", e));
+    send_response(webview, request_id, result);
+}
+
+/// Sends the result of the query identified by `request_id` to the
+/// `rpatchur.onResponse` function of the UI.
+fn send_response<T: Serialize>(
+    webview: &mut WebView<WebViewUserData>,
+    request_id: Value,
+    result: Result<T, String>,
+) {
+    let response = build_response(request_id, result);
+    let res = webview.eval(&format!(
+        "window.rpatchur && typeof window.rpatchur.onResponse === \"function\" && window.rpatchur.onResponse({})",
+        to_js_literal(&response)
+    ));
+    if let Err(e) = res {
+        log::warn!("Failed to dispatch response: {}.", e);
+    }
+}
+
+/// Builds the response to a query. Successful queries have a `result` field
+/// while failed ones have an `error` field.
+fn build_response<T: Serialize>(request_id: Value, result: Result<T, String>) -> Value {
+    match result.and_then(|value| serde_json::to_value(value).map_err(|e| e.to_string())) {
+        Ok(value) => json!({ "id": request_id, "result": value }),
+        Err(msg) => {
+            log::warn!("{}", msg);
+            json!({ "id": request_id, "error": msg })
+        }
+    }
+}
+
+/// Starts the game client with the arguments used by the login function.
+fn start_game_client_with_login(webview: &mut WebView<WebViewUserData>, login: &str, token: &str) {
+    let patcher_config = &webview.user_data().patcher_config;
+    if let Some(mut profile) = patcher_config.launch_profile(PLAY_PROFILE_NAME) {
+        profile.arguments = patcher_config.play.login_arguments();
+        start_launch_profile(webview, &profile, Some(login), Some(token));
+    }
+}
+
+/// Starts the launch profile named `name`.
+fn start_launch_profile_by_name(webview: &mut WebView<WebViewUserData>, name: &str) {
+    match webview.user_data().patcher_config.launch_profile(name) {
+        Some(profile) => start_launch_profile(webview, &profile, None, None),
+        None => log::error!("Unknown launch profile '{}'", name),
+    }
+}
+
+/// Starts the executable described by `profile`, with the arguments built out
+/// of its template.
+fn start_launch_profile(
+    webview: &mut WebView<WebViewUserData>,
+    profile: &LaunchProfileConfiguration,
+    login: Option<&str>,
+    token: Option<&str>,
+) {
+    let play_config = &webview.user_data().patcher_config.play;
+    let template_values = TemplateValues {
+        login,
+        token,
+        server: play_config.server.as_deref(),
+        lang: play_config.lang.as_deref(),
+    };
+    let arguments = match render_template(&profile.arguments, &template_values) {
+        Ok(v) => v,
         Err(e) => {
-            log::warn!("Failed to start client: {}", e);
+            log::error!(
+                "Failed to build the arguments of '{}': {:#}",
+                profile.name,
+                e
+            );
+            return;
+        }
+    };
+    let start_options = StartOptions {
+        working_directory: profile.working_directory.as_deref(),
+        environment: Some(&profile.environment),
+        runner: profile.runner.as_ref(),
+    };
+    match start_executable(&profile.path, &arguments, start_options) {
+        Ok(Some(started_process)) => {
+            log::trace!("Launch profile '{}' started", profile.name);
+            let profile_name = profile.name.clone();
+            let exit_on_success = profile.exit_on_success.unwrap_or(false);
+            let web_view_handle = webview.handle();
+            // Check that the process doesn't fail right away, without blocking the UI
+            std::thread::spawn(move || {
+                let startup_result = started_process.wait_for_startup(STARTUP_CHECK_DELAY);
+                let dispatch_res = web_view_handle.dispatch(move |webview| {
+                    match startup_result {
+                        Ok(()) => {
+                            send_event(
+                                webview,
+                                &LaunchEvent::Succeeded {
+                                    profile: profile_name,
+                                },
+                            );
+                            if exit_on_success {
+                                webview.exit();
+                            }
+                        }
+                        Err(e) => report_launch_failure(webview, profile_name, e),
+                    }
+                    Ok(())
+                });
+                if let Err(e) = dispatch_res {
+                    log::warn!("Failed to dispatch launch result: {}.", e);
+                }
+            });
         }
+        Ok(None) => log::trace!("Launch profile '{}' wasn't started", profile.name),
+        Err(e) => report_launch_failure(webview, profile.name.clone(), e),
+    }
+}
+
+/// Notifies the UI that a launch profile couldn't be started.
+fn report_launch_failure(
+    webview: &mut WebView<WebViewUserData>,
+    profile: String,
+    err: anyhow::Error,
+) {
+    log::warn!("Failed to start launch profile '{}': {:#}", profile, err);
+    send_event(
+        webview,
+        &LaunchEvent::Failed {
+            profile,
+            message: format!("{:#}", err),
+        },
+    );
+}
+
+/// Sends `event` to the UI's `rpatchur.onEvent` function (or to the legacy
+/// `patchingStatus*` functions).
+fn send_event(webview: &mut WebView<WebViewUserData>, event: &impl Serialize) {
+    let result = webview.eval(&format!(
+        "{}({})",
+        EVENT_DISPATCHER.trim(),
+        to_js_literal(event)
+    ));
+    if let Err(e) = result {
+        log::warn!("Failed to dispatch event: {}.", e);
+    }
+}
+
+/// Serializes `value` into a JSON document that can be safely embedded into
+/// JavaScript code.
+fn to_js_literal(value: &impl Serialize) -> String {
+    match serde_json::to_string(value) {
+        // These are valid in JSON strings but not in JavaScript ones
+        Ok(json) => json
+            .replace('\u{2028}', "\\u2028")
+            .replace('\u{2029}', "\\u2029"),
+        Err(e) => {
+            log::warn!("Failed to serialize value: {}.", e);
+            "null".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+
+    fn web_configuration(index_url: String) -> WebConfiguration {
+        WebConfiguration {
+            index_url,
+            index_timeout: Some(1),
+            fallback_index_path: None,
+            engine: serde_yaml::from_str("patch_servers: []").unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_to_js_literal() {
+        assert_eq!(
+            r#""\"quoted\" \\ \u2028""#,
+            to_js_literal(&"\"quoted\" \\ \u{2028}")
+        );
+        assert_eq!(r#"["a","b"]"#, to_js_literal(&vec!["a", "b"]));
+    }
+
+    #[test]
+    fn test_build_response() {
+        assert_eq!(
+            json!({"id": 1, "result": [1, 2]}),
+            build_response(json!(1), Ok(vec![1, 2]))
+        );
+        assert_eq!(
+            json!({"id": "abc", "error": "Failed"}),
+            build_response::<()>(json!("abc"), Err("Failed".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_select_ui_source() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", "/index.html"))
+                .respond_with(status_code(200).body("<html></html>")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", "/missing.html"))
+                .respond_with(status_code(404)),
+        );
+        let mut web_config = web_configuration(server.url_str("/index.html"));
+        assert_eq!(UiSource::Remote, select_ui_source(&web_config));
+
+        web_config.index_url = server.url_str("/missing.html");
+        assert_eq!(UiSource::Embedded, select_ui_source(&web_config));
+
+        web_config.fallback_index_path = Some("resources/offline.html".to_string());
+        match select_ui_source(&web_config) {
+            UiSource::LocalFile(url) => assert!(url.starts_with("file://")),
+            ui_source => panic!("Unexpected UI source: {:?}", ui_source),
+        }
+
+        web_config.fallback_index_path = Some("resources/missing.html".to_string());
+        assert_eq!(UiSource::Embedded, select_ui_source(&web_config));
     }
 }